@@ -1,15 +1,31 @@
+#[cfg(feature = "arrow")]
+mod arrow;
+pub mod compare;
+
 use crate::{
     time_range::{BucketsRange, FORMAT_STR_SECONDS},
-    user_tag::Action,
+    user_tag::{Action, UserTag},
+};
+use anyhow::Context;
+use chrono::{DateTime, Duration, NaiveDateTime, Timelike, Utc};
+use serde::{
+    de::{self, IntoDeserializer},
+    ser::SerializeStruct,
+    Deserialize, Serialize, Serializer,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display, Formatter},
 };
-use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
-use std::fmt::{self, Display, Formatter};
 
 #[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Aggregate {
     Count,
     SumPrice,
+    // Number of distinct product ids in the bucket. The store may estimate it (e.g. with a
+    // HyperLogLog), so it is not guaranteed to be exact for high cardinalities.
+    DistinctProducts,
 }
 
 impl Display for Aggregate {
@@ -17,21 +33,241 @@ impl Display for Aggregate {
         match self {
             Self::Count => f.write_str("COUNT"),
             Self::SumPrice => f.write_str("SUM_PRICE"),
+            Self::DistinctProducts => f.write_str("DISTINCT_PRODUCTS"),
         }
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct AggregatesQuery {
     pub time_range: BucketsRange,
     pub action: Action,
     pub origin: Option<String>,
     pub brand_id: Option<String>,
     pub category_id: Option<String>,
+    pub country: Option<String>,
+    pub product_id: Option<i32>,
     pub aggregates: Vec<Aggregate>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    pub include_query: bool,
+    // The count of buckets without stored data is only replied on request, the default reply is
+    // exactly `{columns, rows}`.
+    pub include_zero_filled: bool,
+    pub compact: bool,
+    // Queries have a single action, so its column can be left out of the reply on request.
+    pub omit_action: bool,
+}
+
+fn parse_value<'de, T: Deserialize<'de>>(key: &str, value: &'de str) -> anyhow::Result<T> {
+    let deserializer: de::value::StrDeserializer<de::value::Error> = value.into_deserializer();
+    T::deserialize(deserializer).with_context(|| format!("invalid value of {}", key))
+}
+
+fn set_once<T>(slot: &mut Option<T>, key: &str, value: T) -> anyhow::Result<()> {
+    anyhow::ensure!(slot.is_none(), "duplicated {}", key);
+    *slot = Some(value);
+    Ok(())
 }
 
 impl AggregatesQuery {
+    // A query over up to `max_buckets` buckets.
+    pub fn from_pairs(pairs: Vec<(String, String)>, max_buckets: usize) -> anyhow::Result<Self> {
+        Self::parse(pairs, max_buckets)
+    }
+
+    // A query over up to `max_buckets` buckets, to be split into regular queries with `split`.
+    pub fn from_range_pairs(
+        pairs: Vec<(String, String)>,
+        max_buckets: usize,
+    ) -> anyhow::Result<Self> {
+        if let Some((key, _)) = pairs
+            .iter()
+            .find(|(key, _)| key == "offset" || key == "limit")
+        {
+            anyhow::bail!("unexpected {} in a range query", key);
+        }
+
+        Self::parse(pairs, max_buckets)
+    }
+
+    fn parse(pairs: Vec<(String, String)>, max_buckets: usize) -> anyhow::Result<Self> {
+        let mut time_range = None;
+        let mut action = None;
+        let mut origin = None;
+        let mut brand_id = None;
+        let mut category_id = None;
+        let mut country = None;
+        let mut product_id = None;
+        let mut aggregates = vec![];
+        let mut offset = None;
+        let mut limit = None;
+        let mut include_query = None;
+        let mut include_zero_filled = None;
+        let mut compact = None;
+        let mut omit_action = None;
+        let mut inclusive_end = None;
+
+        for (key, value) in pairs {
+            match key.as_str() {
+                "time_range" => {
+                    let value = BucketsRange::parse(&value, max_buckets)
+                        .with_context(|| format!("invalid value of {}", key))?;
+                    set_once(&mut time_range, &key, value)?;
+                }
+                "action" => set_once(&mut action, &key, parse_value(&key, &value)?)?,
+                "product_id" => {
+                    let value = value
+                        .parse()
+                        .with_context(|| format!("invalid value of {}", key))?;
+                    set_once(&mut product_id, &key, value)?;
+                }
+                "aggregates" => aggregates.push(parse_value(&key, &value)?),
+                "offset" | "limit" => {
+                    let value = value
+                        .parse()
+                        .with_context(|| format!("invalid value of {}", key))?;
+                    let slot = match key.as_str() {
+                        "offset" => &mut offset,
+                        _ => &mut limit,
+                    };
+                    set_once(slot, &key, value)?;
+                }
+                "include_query"
+                | "include_zero_filled"
+                | "compact"
+                | "omit_action"
+                | "inclusive_end" => {
+                    let value = value
+                        .parse()
+                        .with_context(|| format!("invalid value of {}", key))?;
+                    let slot = match key.as_str() {
+                        "include_query" => &mut include_query,
+                        "include_zero_filled" => &mut include_zero_filled,
+                        "compact" => &mut compact,
+                        "omit_action" => &mut omit_action,
+                        _ => &mut inclusive_end,
+                    };
+                    set_once(slot, &key, value)?;
+                }
+                "origin" | "brand_id" | "category_id" | "country" => {
+                    // An empty value would be indistinguishable from an absent dimension.
+                    anyhow::ensure!(!value.is_empty(), "empty value of {}", key);
+                    let slot = match key.as_str() {
+                        "origin" => &mut origin,
+                        "brand_id" => &mut brand_id,
+                        "category_id" => &mut category_id,
+                        _ => &mut country,
+                    };
+                    set_once(slot, &key, value)?;
+                }
+                _ => {}
+            }
+        }
+
+        anyhow::ensure!(!aggregates.is_empty(), "missing aggregates");
+
+        let mut time_range: BucketsRange = time_range.context("missing time_range")?;
+        if inclusive_end.unwrap_or(false) {
+            time_range = time_range.with_inclusive_end();
+            anyhow::ensure!(
+                time_range.buckets_count() <= max_buckets,
+                "time_range with its end is longer than {} buckets",
+                max_buckets
+            );
+        }
+        if let Some(offset) = offset {
+            anyhow::ensure!(
+                offset < time_range.buckets_count(),
+                "offset out of the time range"
+            );
+        }
+        anyhow::ensure!(limit != Some(0), "limit must be positive");
+
+        Ok(Self {
+            time_range,
+            action: action.context("missing action")?,
+            origin,
+            brand_id,
+            category_id,
+            country,
+            product_id,
+            aggregates,
+            offset,
+            limit,
+            include_query: include_query.unwrap_or(false),
+            include_zero_filled: include_zero_filled.unwrap_or(false),
+            compact: compact.unwrap_or(false),
+            omit_action: omit_action.unwrap_or(false),
+        })
+    }
+
+    // A query for the single bucket starting at `time`, the remaining pairs are the same as in
+    // `from_pairs` except for pagination.
+    pub fn from_bucket_pairs(pairs: Vec<(String, String)>) -> anyhow::Result<Self> {
+        let mut time = None;
+        let mut rest = Vec::with_capacity(pairs.len());
+
+        for (key, value) in pairs {
+            match key.as_str() {
+                "time" => {
+                    let value = NaiveDateTime::parse_from_str(&value, FORMAT_STR_SECONDS)
+                        .with_context(|| format!("invalid value of {}", key))?;
+                    set_once(&mut time, &key, value)?;
+                }
+                "time_range" | "offset" | "limit" | "inclusive_end" => {
+                    anyhow::bail!("unexpected {} in a single bucket query", key)
+                }
+                _ => rest.push((key, value)),
+            }
+        }
+
+        let time = time.context("missing time")?;
+        let time_range = format!(
+            "{}_{}",
+            time.format(FORMAT_STR_SECONDS),
+            (time + Duration::minutes(1)).format(FORMAT_STR_SECONDS)
+        );
+        rest.push(("time_range".into(), time_range));
+
+        Self::parse(rest, 1)
+    }
+
+    pub fn split(&self, max_buckets: usize) -> Vec<AggregatesQuery> {
+        self.time_range
+            .split(max_buckets)
+            .map(|time_range| AggregatesQuery {
+                time_range,
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    // Joins replies to the queries returned from `split`, in order.
+    pub fn make_merged_reply(self, parts: Vec<AggregatesReply>) -> anyhow::Result<AggregatesReply> {
+        let mut rows = Vec::with_capacity(self.time_range.buckets_count());
+        let mut zero_filled = 0;
+        let mut next_bucket = *self.time_range.from();
+
+        for part in parts {
+            anyhow::ensure!(
+                *part.query.time_range.from() == next_bucket,
+                "replies are not consecutive"
+            );
+            next_bucket = part.query.time_range.end();
+            rows.extend(part.rows);
+            zero_filled += part.zero_filled;
+        }
+        anyhow::ensure!(
+            next_bucket == self.time_range.end(),
+            "replies do not cover the time range"
+        );
+
+        let mut reply = self.make_reply(rows)?;
+        reply.zero_filled = zero_filled;
+        Ok(reply)
+    }
+
     pub fn aggregates(&self) -> &[Aggregate] {
         &self.aggregates
     }
@@ -42,52 +278,232 @@ impl AggregatesQuery {
             "invalid rows count"
         );
 
-        let expected_sum_price = self.aggregates.contains(&Aggregate::SumPrice);
-        let expected_count = self.aggregates.contains(&Aggregate::Count);
         for row in &rows {
-            anyhow::ensure!(
-                !expected_sum_price || row.sum_price.is_some(),
-                "row does not contain sum price"
-            );
-            anyhow::ensure!(
-                !expected_count || row.count.is_some(),
-                "row does not contain count"
-            );
+            for aggr in &self.aggregates {
+                anyhow::ensure!(row.value(*aggr).is_some(), "row does not contain {}", aggr);
+            }
         }
 
-        Ok(AggregatesReply { query: self, rows })
+        Ok(AggregatesReply {
+            query: self,
+            rows,
+            zero_filled: 0,
+        })
+    }
+
+    fn zero_row(&self) -> AggregatesRow {
+        AggregatesRow {
+            sum_price: self.aggregates.contains(&Aggregate::SumPrice).then_some(0),
+            count: self.aggregates.contains(&Aggregate::Count).then_some(0),
+            distinct_products: self
+                .aggregates
+                .contains(&Aggregate::DistinctProducts)
+                .then_some(0),
+        }
+    }
+
+    fn matches(&self, tag: &UserTag) -> bool {
+        fn filter_matches<T: PartialEq + ?Sized>(filter: Option<&T>, value: &T) -> bool {
+            filter.is_none() || filter == Some(value)
+        }
+
+        tag.action == self.action
+            && self.time_range.contains(&tag.time)
+            && filter_matches(self.origin.as_deref(), &tag.origin)
+            && filter_matches(self.brand_id.as_deref(), &tag.product_info.brand_id)
+            && filter_matches(self.category_id.as_deref(), &tag.product_info.category_id)
+            && filter_matches(self.country.as_deref(), &tag.country)
+            && filter_matches(self.product_id.as_ref(), &tag.product_info.product_id)
+    }
+
+    // Rows of the buckets the matching tags fall into, e.g. to cross-check stored aggregates
+    // against stored profiles. Buckets without matching tags are left out.
+    pub fn rows_from_tags<'a, I>(&self, tags: I) -> HashMap<DateTime<Utc>, AggregatesRow>
+    where
+        I: IntoIterator<Item = &'a UserTag>,
+    {
+        let mut buckets: HashMap<DateTime<Utc>, (usize, usize, HashSet<i32>)> = HashMap::new();
+        for tag in tags.into_iter().filter(|tag| self.matches(tag)) {
+            let bucket = tag
+                .time
+                .with_second(0)
+                .and_then(|time| time.with_nanosecond(0))
+                .expect("a zero second and nanosecond should be valid");
+            let (count, sum_price, products) = buckets.entry(bucket).or_default();
+            *count += 1;
+            // Prices are non-negative in the spec.
+            *sum_price += tag.product_info.price.max(0) as usize;
+            products.insert(tag.product_info.product_id);
+        }
+
+        buckets
+            .into_iter()
+            .map(|(bucket, (count, sum_price, products))| {
+                let zero = self.zero_row();
+                let row = AggregatesRow {
+                    count: zero.count.map(|_| count),
+                    sum_price: zero.sum_price.map(|_| sum_price),
+                    distinct_products: zero.distinct_products.map(|_| products.len()),
+                };
+                (bucket, row)
+            })
+            .collect()
+    }
+
+    // Buckets missing from `buckets` get zero rows. So do the queried values missing from a row,
+    // e.g. a bucket record without a `sum_price` bin, instead of failing the whole reply.
+    pub fn make_zero_filled_reply(
+        self,
+        mut buckets: HashMap<DateTime<Utc>, AggregatesRow>,
+    ) -> anyhow::Result<AggregatesReply> {
+        let mut zero_filled = 0;
+        let rows = self
+            .time_range
+            .bucket_starts()
+            .map(|start| match buckets.remove(&start) {
+                Some(row) => {
+                    let zero = self.zero_row();
+                    AggregatesRow {
+                        sum_price: row.sum_price.or(zero.sum_price),
+                        count: row.count.or(zero.count),
+                        distinct_products: row.distinct_products.or(zero.distinct_products),
+                    }
+                }
+                None => {
+                    zero_filled += 1;
+                    self.zero_row()
+                }
+            })
+            .collect();
+        anyhow::ensure!(buckets.is_empty(), "bucket outside of the time range");
+
+        let mut reply = self.make_reply(rows)?;
+        reply.zero_filled = zero_filled;
+        Ok(reply)
     }
 }
 
-#[derive(Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct AggregatesRow {
     pub sum_price: Option<usize>,
     pub count: Option<usize>,
+    pub distinct_products: Option<usize>,
+}
+
+impl AggregatesRow {
+    fn value(&self, aggr: Aggregate) -> Option<usize> {
+        match aggr {
+            Aggregate::Count => self.count,
+            Aggregate::SumPrice => self.sum_price,
+            Aggregate::DistinctProducts => self.distinct_products,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EchoedQuery<'a> {
+    time_range: String,
+    action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    origin: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brand_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product_id: Option<i32>,
+    aggregates: &'a [Aggregate],
+}
+
+impl<'a> From<&'a AggregatesQuery> for EchoedQuery<'a> {
+    fn from(query: &'a AggregatesQuery) -> Self {
+        Self {
+            time_range: format!(
+                "{}_{}",
+                query.time_range.from().format(FORMAT_STR_SECONDS),
+                query.time_range.to().format(FORMAT_STR_SECONDS)
+            ),
+            action: query.action,
+            origin: query.origin.as_deref(),
+            brand_id: query.brand_id.as_deref(),
+            category_id: query.category_id.as_deref(),
+            country: query.country.as_deref(),
+            product_id: query.product_id,
+            aggregates: &query.aggregates,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct AggregatesReply {
     query: AggregatesQuery,
     rows: Vec<AggregatesRow>,
+    zero_filled: usize,
+}
+
+impl AggregatesReply {
+    // Values of the action and dimensions, the same in every row.
+    fn constants(&self) -> Vec<(&'static str, String)> {
+        let query = &self.query;
+        let mut constants = Vec::with_capacity(6);
+        if !query.omit_action {
+            constants.push(("action", query.action.to_string()));
+        }
+        if let Some(origin) = query.origin.as_ref() {
+            constants.push(("origin", origin.clone()));
+        }
+        if let Some(brand_id) = query.brand_id.as_ref() {
+            constants.push(("brand_id", brand_id.clone()));
+        }
+        if let Some(category_id) = query.category_id.as_ref() {
+            constants.push(("category_id", category_id.clone()));
+        }
+        if let Some(country) = query.country.as_ref() {
+            constants.push(("country", country.clone()));
+        }
+        if let Some(product_id) = query.product_id {
+            constants.push(("product_id", product_id.to_string()));
+        }
+        constants
+    }
+}
+
+struct Constants<'a>(&'a [(&'static str, String)]);
+
+impl Serialize for Constants<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(name, value)| (name, value)))
+    }
 }
 
 impl Serialize for AggregatesReply {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut root = serializer.serialize_struct("AggregatesReply", 2)?;
+        let paginated = self.query.offset.is_some() || self.query.limit.is_some();
+        let fields = 2
+            + usize::from(self.query.compact)
+            + usize::from(paginated)
+            + usize::from(self.query.include_zero_filled)
+            + usize::from(self.query.include_query);
+        let mut root = serializer.serialize_struct("AggregatesReply", fields)?;
+
+        // In the compact form the constant values are listed once instead of in every row.
+        let constants = self.constants();
+        let row_constants = if self.query.compact {
+            root.serialize_field("constants", &Constants(&constants))?;
+            &[][..]
+        } else {
+            &constants[..]
+        };
 
         let columns = {
-            let mut columns: Vec<String> = Vec::with_capacity(5 + self.query.aggregates.len());
+            let mut columns: Vec<String> =
+                Vec::with_capacity(1 + row_constants.len() + self.query.aggregates.len());
 
             columns.push("1m_bucket".into());
-            columns.push("action".into());
-            if self.query.origin.is_some() {
-                columns.push("origin".into());
-            }
-            if self.query.brand_id.is_some() {
-                columns.push("brand_id".into());
-            }
-            if self.query.category_id.is_some() {
-                columns.push("category_id".into());
+            for (name, _) in row_constants {
+                columns.push(name.to_string());
             }
             for aggr in &self.query.aggregates {
                 columns.push(aggr.to_string());
@@ -100,29 +516,21 @@ impl Serialize for AggregatesReply {
         let rows = {
             let mut rows: Vec<Vec<String>> = Vec::with_capacity(self.rows.len());
 
-            for (row, bucket) in self.rows.iter().zip(self.query.time_range.bucket_starts()) {
+            let page = self
+                .rows
+                .iter()
+                .zip(self.query.time_range.bucket_starts())
+                .skip(self.query.offset.unwrap_or(0))
+                .take(self.query.limit.unwrap_or(usize::MAX));
+            for (row, bucket) in page {
                 let mut values: Vec<String> = Vec::with_capacity(columns.len());
 
                 values.push(bucket.format(FORMAT_STR_SECONDS).to_string());
-                values.push(self.query.action.to_string());
-                if let Some(origin) = self.query.origin.as_ref() {
-                    values.push(origin.clone());
-                }
-                if let Some(brand_id) = self.query.brand_id.as_ref() {
-                    values.push(brand_id.clone());
-                }
-                if let Some(category_id) = self.query.category_id.as_ref() {
-                    values.push(category_id.clone());
+                for (_, value) in row_constants {
+                    values.push(value.clone());
                 }
                 for aggr in &self.query.aggregates {
-                    match aggr {
-                        Aggregate::Count => {
-                            values.push(row.count.unwrap().to_string());
-                        }
-                        Aggregate::SumPrice => {
-                            values.push(row.sum_price.unwrap().to_string());
-                        }
-                    }
+                    values.push(row.value(*aggr).unwrap().to_string());
                 }
 
                 rows.push(values)
@@ -132,6 +540,18 @@ impl Serialize for AggregatesReply {
         };
         root.serialize_field("rows", &rows)?;
 
+        if paginated {
+            root.serialize_field("total_rows", &self.rows.len())?;
+        }
+
+        if self.query.include_zero_filled {
+            root.serialize_field("zero_filled", &self.zero_filled)?;
+        }
+
+        if self.query.include_query {
+            root.serialize_field("query", &EchoedQuery::from(&self.query))?;
+        }
+
         root.end()
     }
 }
@@ -139,6 +559,193 @@ impl Serialize for AggregatesReply {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{
+        test_utils::{self, make_pairs},
+        time_range::MAX_RANGE_BUCKETS,
+    };
+    use chrono::Timelike;
+
+    #[test]
+    fn from_pairs() {
+        let query = AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+                ("action", "BUY"),
+                ("origin", "origin"),
+                ("aggregates", "COUNT"),
+                ("aggregates", "SUM_PRICE"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        assert_eq!(query.time_range.buckets_count(), 2);
+        assert_eq!(query.origin.as_deref(), Some("origin"));
+        assert_eq!(query.brand_id, None);
+        assert_eq!(query.aggregates, [Aggregate::Count, Aggregate::SumPrice]);
+
+        // Empty dimension value.
+        AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+                ("action", "BUY"),
+                ("origin", ""),
+                ("aggregates", "COUNT"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+
+        // Duplicated dimension.
+        AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+                ("action", "BUY"),
+                ("brand_id", "a"),
+                ("brand_id", "b"),
+                ("aggregates", "COUNT"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+
+        // Missing aggregates.
+        AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+                ("action", "BUY"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+
+        // Invalid action.
+        AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+                ("action", "CLICK"),
+                ("aggregates", "COUNT"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn inclusive_end() {
+        let pairs = |time_range: &str, inclusive_end: &str| {
+            make_pairs(&[
+                ("time_range", time_range),
+                ("action", "BUY"),
+                ("aggregates", "COUNT"),
+                ("inclusive_end", inclusive_end),
+            ])
+        };
+
+        let exclusive = AggregatesQuery::from_pairs(
+            pairs("2022-03-22T12:15:00_2022-03-22T12:17:00", "false"),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        let inclusive = AggregatesQuery::from_pairs(
+            pairs("2022-03-22T12:15:00_2022-03-22T12:17:00", "true"),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        assert_eq!(exclusive.time_range.buckets_count(), 2);
+        assert_eq!(inclusive.time_range.buckets_count(), 3);
+
+        let row = AggregatesRow {
+            sum_price: None,
+            count: Some(1),
+            distinct_products: None,
+        };
+        exclusive
+            .clone()
+            .make_reply(vec![row.clone(); 3])
+            .unwrap_err();
+        let reply = inclusive.clone().make_reply(vec![row.clone(); 3]).unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert_eq!(serialized["rows"][2][0], "2022-03-22T12:17:00");
+
+        let parts = inclusive
+            .split(2)
+            .into_iter()
+            .map(|part| {
+                let rows = vec![row.clone(); part.time_range.buckets_count()];
+                part.make_reply(rows).unwrap()
+            })
+            .collect();
+        assert_eq!(inclusive.make_merged_reply(parts).unwrap().rows.len(), 3);
+
+        // Including the end bucket must not exceed the buckets limit.
+        AggregatesQuery::from_pairs(
+            pairs("2022-03-22T12:15:00_2022-03-22T12:25:00", "true"),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn from_bucket_pairs() {
+        let range_query = AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:18:00"),
+                ("action", "VIEW"),
+                ("brand_id", "brand"),
+                ("aggregates", "COUNT"),
+                ("aggregates", "SUM_PRICE"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        let bucket_query = AggregatesQuery::from_bucket_pairs(make_pairs(&[
+            ("time", "2022-03-22T12:16:00"),
+            ("action", "VIEW"),
+            ("brand_id", "brand"),
+            ("aggregates", "COUNT"),
+            ("aggregates", "SUM_PRICE"),
+        ]))
+        .unwrap();
+        assert_eq!(bucket_query.time_range.buckets_count(), 1);
+
+        let bucket = *range_query.time_range.from() + Duration::minutes(1);
+        let make_buckets = || {
+            HashMap::from([(
+                bucket,
+                AggregatesRow {
+                    sum_price: Some(500),
+                    count: Some(5),
+                    distinct_products: None,
+                },
+            )])
+        };
+
+        let range_reply = range_query.make_zero_filled_reply(make_buckets()).unwrap();
+        let bucket_reply = bucket_query.make_zero_filled_reply(make_buckets()).unwrap();
+        let range_reply = serde_json::to_value(&range_reply).unwrap();
+        let bucket_reply = serde_json::to_value(&bucket_reply).unwrap();
+        assert_eq!(bucket_reply["columns"], range_reply["columns"]);
+        assert_eq!(
+            bucket_reply["rows"],
+            serde_json::json!([range_reply["rows"][1]])
+        );
+
+        // Not a full minute.
+        AggregatesQuery::from_bucket_pairs(make_pairs(&[
+            ("time", "2022-03-22T12:16:30"),
+            ("action", "VIEW"),
+            ("aggregates", "COUNT"),
+        ]))
+        .unwrap_err();
+
+        // Time range instead of time.
+        AggregatesQuery::from_bucket_pairs(make_pairs(&[
+            ("time_range", "2022-03-22T12:15:00_2022-03-22T12:16:00"),
+            ("action", "VIEW"),
+            ("aggregates", "COUNT"),
+        ]))
+        .unwrap_err();
+    }
 
     #[test]
     fn make_reply() {
@@ -150,7 +757,15 @@ mod test {
             origin: None,
             brand_id: None,
             category_id: None,
+            country: None,
+            product_id: None,
             aggregates: vec![Aggregate::Count],
+            offset: None,
+            limit: None,
+            include_query: false,
+            include_zero_filled: false,
+            compact: false,
+            omit_action: false,
         };
 
         query
@@ -159,10 +774,12 @@ mod test {
                 AggregatesRow {
                     sum_price: None,
                     count: Some(1),
+                    distinct_products: None,
                 },
                 AggregatesRow {
                     sum_price: Some(2),
                     count: Some(4),
+                    distinct_products: None,
                 },
             ])
             .unwrap();
@@ -173,6 +790,7 @@ mod test {
             .make_reply(vec![AggregatesRow {
                 sum_price: None,
                 count: Some(1),
+                distinct_products: None,
             }])
             .unwrap_err();
 
@@ -182,12 +800,501 @@ mod test {
                 AggregatesRow {
                     sum_price: None,
                     count: None,
+                    distinct_products: None,
                 },
                 AggregatesRow {
                     sum_price: Some(2),
                     count: None,
+                    distinct_products: None,
                 },
             ])
             .unwrap_err();
     }
+
+    #[test]
+    fn serialize_reply_with_country() {
+        let time_range: BucketsRange =
+            serde_json::from_str("\"2022-03-22T12:15:00_2022-03-22T12:16:00\"").unwrap();
+        let query = AggregatesQuery {
+            time_range,
+            action: Action::View,
+            origin: Some("origin".into()),
+            brand_id: None,
+            category_id: None,
+            country: Some("PL".into()),
+            product_id: None,
+            aggregates: vec![Aggregate::Count],
+            offset: None,
+            limit: None,
+            include_query: false,
+            include_zero_filled: false,
+            compact: false,
+            omit_action: false,
+        };
+
+        let reply = query
+            .make_reply(vec![AggregatesRow {
+                sum_price: None,
+                count: Some(3),
+                distinct_products: None,
+            }])
+            .unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        let expected = serde_json::json!({
+            "columns": ["1m_bucket", "action", "origin", "country", "COUNT"],
+            "rows": [["2022-03-22T12:15:00", "VIEW", "origin", "PL", "3"]],
+        });
+        assert_eq!(serialized, expected);
+    }
+
+    #[test]
+    fn serialize_reply_with_product() {
+        let time_range: BucketsRange =
+            serde_json::from_str("\"2022-03-22T12:15:00_2022-03-22T12:17:00\"").unwrap();
+        let query = AggregatesQuery {
+            time_range,
+            action: Action::Buy,
+            origin: None,
+            brand_id: Some("brand".into()),
+            category_id: None,
+            country: None,
+            product_id: Some(42),
+            aggregates: vec![Aggregate::SumPrice, Aggregate::Count],
+            offset: None,
+            limit: None,
+            include_query: false,
+            include_zero_filled: false,
+            compact: false,
+            omit_action: false,
+        };
+
+        let reply = query
+            .make_reply(vec![
+                AggregatesRow {
+                    sum_price: Some(0),
+                    count: Some(0),
+                    distinct_products: None,
+                },
+                AggregatesRow {
+                    sum_price: Some(100),
+                    count: Some(2),
+                    distinct_products: None,
+                },
+            ])
+            .unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        let expected = serde_json::json!({
+            "columns": ["1m_bucket", "action", "brand_id", "product_id", "SUM_PRICE", "COUNT"],
+            "rows": [
+                ["2022-03-22T12:15:00", "BUY", "brand", "42", "0", "0"],
+                ["2022-03-22T12:16:00", "BUY", "brand", "42", "100", "2"],
+            ],
+        });
+        assert_eq!(serialized, expected);
+    }
+
+    #[test]
+    fn paginate_reply() {
+        let query = AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:20:00"),
+                ("action", "VIEW"),
+                ("aggregates", "COUNT"),
+                ("offset", "1"),
+                ("limit", "2"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        let rows = (0..5)
+            .map(|count| AggregatesRow {
+                sum_price: None,
+                count: Some(count),
+                distinct_products: None,
+            })
+            .collect();
+
+        let reply = query.make_reply(rows).unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        let expected = serde_json::json!({
+            "columns": ["1m_bucket", "action", "COUNT"],
+            "rows": [
+                ["2022-03-22T12:16:00", "VIEW", "1"],
+                ["2022-03-22T12:17:00", "VIEW", "2"],
+            ],
+            "total_rows": 5,
+        });
+        assert_eq!(serialized, expected);
+
+        // Offset out of the time range.
+        AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:20:00"),
+                ("action", "VIEW"),
+                ("aggregates", "COUNT"),
+                ("offset", "5"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn include_query() {
+        let pairs = [
+            ("time_range", "2022-03-22T12:15:00_2022-03-22T12:16:00"),
+            ("action", "VIEW"),
+            ("category_id", "category"),
+            ("aggregates", "SUM_PRICE"),
+        ];
+        let rows = || {
+            vec![AggregatesRow {
+                sum_price: Some(10),
+                count: None,
+                distinct_products: None,
+            }]
+        };
+
+        let mut with_flag = make_pairs(&pairs);
+        with_flag.push(("include_query".into(), "true".into()));
+        let query = AggregatesQuery::from_pairs(with_flag, MAX_RANGE_BUCKETS).unwrap();
+        let serialized = serde_json::to_value(query.make_reply(rows()).unwrap()).unwrap();
+        let expected = serde_json::json!({
+            "time_range": "2022-03-22T12:15:00_2022-03-22T12:16:00",
+            "action": "VIEW",
+            "category_id": "category",
+            "aggregates": ["SUM_PRICE"],
+        });
+        assert_eq!(serialized["query"], expected);
+
+        let query = AggregatesQuery::from_pairs(make_pairs(&pairs), MAX_RANGE_BUCKETS).unwrap();
+        let serialized = serde_json::to_value(query.make_reply(rows()).unwrap()).unwrap();
+        assert!(serialized.get("query").is_none());
+    }
+
+    #[test]
+    fn compact_reply() {
+        let pairs = [
+            ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+            ("action", "BUY"),
+            ("origin", "origin"),
+            ("country", "PL"),
+            ("aggregates", "COUNT"),
+        ];
+        let rows = || {
+            vec![
+                AggregatesRow {
+                    sum_price: None,
+                    count: Some(1),
+                    distinct_products: None,
+                },
+                AggregatesRow {
+                    sum_price: None,
+                    count: Some(2),
+                    distinct_products: None,
+                },
+            ]
+        };
+
+        let mut with_flag = make_pairs(&pairs);
+        with_flag.push(("compact".into(), "true".into()));
+        let query = AggregatesQuery::from_pairs(with_flag, MAX_RANGE_BUCKETS).unwrap();
+        let compact = serde_json::to_value(query.make_reply(rows()).unwrap()).unwrap();
+        let expected = serde_json::json!({
+            "constants": {"action": "BUY", "origin": "origin", "country": "PL"},
+            "columns": ["1m_bucket", "COUNT"],
+            "rows": [["2022-03-22T12:15:00", "1"], ["2022-03-22T12:16:00", "2"]],
+        });
+        assert_eq!(compact, expected);
+
+        let query = AggregatesQuery::from_pairs(make_pairs(&pairs), MAX_RANGE_BUCKETS).unwrap();
+        let verbose = serde_json::to_value(query.make_reply(rows()).unwrap()).unwrap();
+
+        // Expanding the constants back into every row gives the verbose reply.
+        let constants = compact["constants"].as_object().unwrap();
+        let compact_columns = compact["columns"].as_array().unwrap();
+        let verbose_columns = verbose["columns"].as_array().unwrap();
+        assert_eq!(
+            verbose_columns.len(),
+            compact_columns.len() + constants.len()
+        );
+        let compact_rows = compact["rows"].as_array().unwrap();
+        let verbose_rows = verbose["rows"].as_array().unwrap();
+        assert_eq!(compact_rows.len(), verbose_rows.len());
+        for (compact_row, verbose_row) in compact_rows.iter().zip(verbose_rows) {
+            for (idx, column) in verbose_columns.iter().enumerate() {
+                let column = column.as_str().unwrap();
+                let value = match constants.get(column) {
+                    Some(value) => value,
+                    None => {
+                        let pos = compact_columns.iter().position(|c| c == column).unwrap();
+                        &compact_row[pos]
+                    }
+                };
+                assert_eq!(value, &verbose_row[idx]);
+            }
+        }
+    }
+
+    #[test]
+    fn merge_split_replies() {
+        let pairs = make_pairs(&[
+            ("time_range", "2022-03-22T12:15:00_2022-03-22T12:40:00"),
+            ("action", "BUY"),
+            ("aggregates", "COUNT"),
+        ]);
+        AggregatesQuery::from_pairs(pairs.clone(), MAX_RANGE_BUCKETS).unwrap_err();
+        AggregatesQuery::from_range_pairs(pairs.clone(), 20).unwrap_err();
+        let query = AggregatesQuery::from_range_pairs(pairs.clone(), 60).unwrap();
+
+        let mut with_limit = pairs;
+        with_limit.push(("limit".into(), "5".into()));
+        AggregatesQuery::from_range_pairs(with_limit, 60).unwrap_err();
+
+        let chunks = query.split(10);
+        assert_eq!(chunks.len(), 3);
+        let parts = chunks
+            .into_iter()
+            .map(|chunk| {
+                let rows = chunk
+                    .time_range
+                    .bucket_starts()
+                    .map(|start| AggregatesRow {
+                        sum_price: None,
+                        count: Some(start.minute() as usize),
+                        distinct_products: None,
+                    })
+                    .collect();
+                chunk.make_reply(rows).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let reply = query.clone().make_merged_reply(parts).unwrap();
+        let counts = reply
+            .rows
+            .iter()
+            .map(|row| row.count.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, (15..40).collect::<Vec<_>>());
+
+        let mut parts = query
+            .split(10)
+            .into_iter()
+            .map(|chunk| chunk.make_zero_filled_reply(Default::default()).unwrap())
+            .collect::<Vec<_>>();
+        parts.swap(0, 1);
+        query.clone().make_merged_reply(parts).unwrap_err();
+        parts = query
+            .split(10)
+            .into_iter()
+            .map(|chunk| chunk.make_zero_filled_reply(Default::default()).unwrap())
+            .collect();
+        parts.pop();
+        query.make_merged_reply(parts).unwrap_err();
+    }
+
+    #[test]
+    fn distinct_products() {
+        let query = AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+                ("action", "VIEW"),
+                ("aggregates", "COUNT"),
+                ("aggregates", "DISTINCT_PRODUCTS"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        assert_eq!(
+            query.aggregates,
+            [Aggregate::Count, Aggregate::DistinctProducts]
+        );
+
+        let missing = (0..2)
+            .map(|_| AggregatesRow {
+                sum_price: None,
+                count: Some(5),
+                distinct_products: None,
+            })
+            .collect();
+        let e = query.clone().make_reply(missing).unwrap_err();
+        assert_eq!(e.to_string(), "row does not contain DISTINCT_PRODUCTS");
+
+        // Five views of three distinct products.
+        let rows = (0..2)
+            .map(|_| AggregatesRow {
+                sum_price: None,
+                count: Some(5),
+                distinct_products: Some(3),
+            })
+            .collect();
+        let reply = serde_json::to_value(query.make_reply(rows).unwrap()).unwrap();
+        let expected = serde_json::json!({
+            "columns": ["1m_bucket", "action", "COUNT", "DISTINCT_PRODUCTS"],
+            "rows": [
+                ["2022-03-22T12:15:00", "VIEW", "5", "3"],
+                ["2022-03-22T12:16:00", "VIEW", "5", "3"],
+            ],
+        });
+        assert_eq!(reply, expected);
+    }
+
+    #[test]
+    fn count_distinct_products() {
+        let query = AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:17:00"),
+                ("action", "VIEW"),
+                ("aggregates", "COUNT"),
+                ("aggregates", "DISTINCT_PRODUCTS"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+
+        let tag = |minute: u32, product_id: i32| {
+            let mut tag = test_utils::sample_tag();
+            tag.time = tag.time.with_minute(minute).unwrap();
+            tag.product_info.product_id = product_id;
+            tag
+        };
+        // Repeated and distinct products in the first bucket, a single repeated one in the second.
+        let tags = [
+            tag(15, 1),
+            tag(15, 2),
+            tag(15, 1),
+            tag(15, 3),
+            tag(15, 2),
+            tag(16, 7),
+            tag(16, 7),
+        ];
+
+        let rows = query.rows_from_tags(&tags);
+        let reply = query.make_zero_filled_reply(rows).unwrap();
+        let reply = serde_json::to_value(reply).unwrap();
+        assert_eq!(
+            reply["rows"],
+            serde_json::json!([
+                ["2022-03-22T12:15:00", "VIEW", "5", "3"],
+                ["2022-03-22T12:16:00", "VIEW", "2", "1"],
+            ])
+        );
+    }
+
+    #[test]
+    fn omit_action() {
+        let pairs = [
+            ("time_range", "2022-03-22T12:15:00_2022-03-22T12:16:00"),
+            ("action", "VIEW"),
+            ("brand_id", "Nike"),
+            ("aggregates", "COUNT"),
+            ("compact", "true"),
+        ];
+        let rows = || {
+            vec![AggregatesRow {
+                sum_price: None,
+                count: Some(3),
+                distinct_products: None,
+            }]
+        };
+
+        let mut with_flag = make_pairs(&pairs);
+        with_flag.push(("omit_action".into(), "true".into()));
+        let query = AggregatesQuery::from_pairs(with_flag, MAX_RANGE_BUCKETS).unwrap();
+        let reply = serde_json::to_value(query.make_reply(rows()).unwrap()).unwrap();
+        let expected = serde_json::json!({
+            "constants": {"brand_id": "Nike"},
+            "columns": ["1m_bucket", "COUNT"],
+            "rows": [["2022-03-22T12:15:00", "3"]],
+        });
+        assert_eq!(reply, expected);
+
+        let query = AggregatesQuery::from_pairs(make_pairs(&pairs), MAX_RANGE_BUCKETS).unwrap();
+        let reply = serde_json::to_value(query.make_reply(rows()).unwrap()).unwrap();
+        assert_eq!(reply["constants"]["action"], "VIEW");
+
+        let mut verbose = make_pairs(&pairs[..4]);
+        verbose.push(("omit_action".into(), "true".into()));
+        let query = AggregatesQuery::from_pairs(verbose, MAX_RANGE_BUCKETS).unwrap();
+        let reply = serde_json::to_value(query.make_reply(rows()).unwrap()).unwrap();
+        assert_eq!(
+            reply["columns"],
+            serde_json::json!(["1m_bucket", "brand_id", "COUNT"])
+        );
+    }
+
+    #[test]
+    fn zero_filled_reply() {
+        let query = AggregatesQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:15:00_2022-03-22T12:18:00"),
+                ("action", "BUY"),
+                ("aggregates", "COUNT"),
+                ("aggregates", "SUM_PRICE"),
+                ("include_zero_filled", "true"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        let buckets = HashMap::from([(
+            query.time_range.from().to_owned() + chrono::Duration::minutes(1),
+            AggregatesRow {
+                sum_price: Some(300),
+                count: Some(2),
+                distinct_products: None,
+            },
+        )]);
+
+        let reply = query.clone().make_zero_filled_reply(buckets).unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        let expected = serde_json::json!({
+            "columns": ["1m_bucket", "action", "COUNT", "SUM_PRICE"],
+            "rows": [
+                ["2022-03-22T12:15:00", "BUY", "0", "0"],
+                ["2022-03-22T12:16:00", "BUY", "2", "300"],
+                ["2022-03-22T12:17:00", "BUY", "0", "0"],
+            ],
+            "zero_filled": 2,
+        });
+        assert_eq!(serialized, expected);
+
+        // A bucket without one of the queried values is kept, with that value zeroed.
+        let buckets = HashMap::from([(
+            query.time_range.from().to_owned(),
+            AggregatesRow {
+                sum_price: None,
+                count: Some(2),
+                distinct_products: None,
+            },
+        )]);
+        let reply = query.clone().make_zero_filled_reply(buckets).unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert_eq!(
+            serialized["rows"][0],
+            serde_json::json!(["2022-03-22T12:15:00", "BUY", "2", "0"])
+        );
+        assert_eq!(serialized["zero_filled"], 2);
+
+        // Without the flag the reply is exactly `{columns, rows}`.
+        let without_flag = AggregatesQuery {
+            include_zero_filled: false,
+            ..query.clone()
+        };
+        let reply = without_flag
+            .make_zero_filled_reply(Default::default())
+            .unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        let keys = serialized.as_object().unwrap().keys().collect::<Vec<_>>();
+        assert_eq!(keys, ["columns", "rows"]);
+
+        // Bucket outside of the time range.
+        let buckets = HashMap::from([(
+            query.time_range.to().to_owned(),
+            AggregatesRow {
+                sum_price: Some(300),
+                count: Some(2),
+                distinct_products: None,
+            },
+        )]);
+        query.make_zero_filled_reply(buckets).unwrap_err();
+    }
 }
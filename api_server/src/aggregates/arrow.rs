@@ -0,0 +1,108 @@
+use super::AggregatesReply;
+use anyhow::Context;
+use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+
+impl AggregatesReply {
+    // One row per bucket, pagination and the compact form do not apply. Bucket starts are
+    // milliseconds since the epoch.
+    pub fn to_arrow(&self) -> anyhow::Result<RecordBatch> {
+        let constants = self.constants();
+        let mut fields = Vec::with_capacity(1 + constants.len() + self.query.aggregates.len());
+        let mut columns: Vec<ArrayRef> = Vec::with_capacity(fields.capacity());
+
+        fields.push(Field::new("1m_bucket", DataType::Int64, false));
+        columns.push(Arc::new(Int64Array::from_iter_values(
+            self.query
+                .time_range
+                .bucket_starts()
+                .map(|start| start.timestamp_millis()),
+        )));
+
+        for (name, value) in &constants {
+            fields.push(Field::new(*name, DataType::Utf8, false));
+            columns.push(Arc::new(StringArray::from(vec![
+                value.as_str();
+                self.rows.len()
+            ])));
+        }
+
+        for aggr in &self.query.aggregates {
+            let values = self.rows.iter().map(|row| row.value(*aggr).unwrap() as u64);
+            fields.push(Field::new(aggr.to_string(), DataType::UInt64, false));
+            columns.push(Arc::new(UInt64Array::from_iter_values(values)));
+        }
+
+        RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+            .context("failed to build the record batch")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aggregates::{AggregatesQuery, AggregatesRow},
+        time_range::MAX_RANGE_BUCKETS,
+    };
+    use arrow_array::Array;
+
+    #[test]
+    fn record_batch() {
+        let query = AggregatesQuery::from_pairs(
+            vec![
+                (
+                    "time_range".into(),
+                    "2022-03-22T12:15:00_2022-03-22T12:18:00".into(),
+                ),
+                ("action".into(), "BUY".into()),
+                ("brand_id".into(), "Nike".into()),
+                ("aggregates".into(), "COUNT".into()),
+                ("aggregates".into(), "SUM_PRICE".into()),
+            ],
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        let rows = (0..3)
+            .map(|i| AggregatesRow {
+                sum_price: Some(i * 100),
+                count: Some(i),
+                distinct_products: None,
+            })
+            .collect();
+        let batch = query.make_reply(rows).unwrap().to_arrow().unwrap();
+
+        let schema = batch.schema();
+        let fields = schema
+            .fields()
+            .iter()
+            .map(|field| (field.name().as_str(), field.data_type().clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                ("1m_bucket", DataType::Int64),
+                ("action", DataType::Utf8),
+                ("brand_id", DataType::Utf8),
+                ("COUNT", DataType::UInt64),
+                ("SUM_PRICE", DataType::UInt64),
+            ]
+        );
+        assert_eq!(batch.num_rows(), 3);
+
+        let buckets = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(buckets.value(1) - buckets.value(0), 60_000);
+        let sums = batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(sums.values().to_vec(), vec![0, 100, 200]);
+        assert_eq!(sums.null_count(), 0);
+    }
+}
@@ -0,0 +1,250 @@
+use super::{set_once, Aggregate, AggregatesQuery, AggregatesReply};
+use crate::time_range::{BucketsRange, FORMAT_STR_SECONDS};
+use anyhow::Context;
+use serde::Serialize;
+
+// The same aggregates over two windows of equal length, e.g. the last 5 minutes and the 5 before.
+#[derive(Debug)]
+pub struct CompareQuery {
+    pub current: AggregatesQuery,
+    pub baseline: AggregatesQuery,
+}
+
+impl CompareQuery {
+    // The same pairs as in a regular query, with an additional `baseline_time_range`.
+    pub fn from_pairs(pairs: Vec<(String, String)>, max_buckets: usize) -> anyhow::Result<Self> {
+        let mut baseline_range = None;
+        let mut rest = Vec::with_capacity(pairs.len());
+
+        for (key, value) in pairs {
+            match key.as_str() {
+                "baseline_time_range" => {
+                    let value = BucketsRange::parse(&value, max_buckets)
+                        .with_context(|| format!("invalid value of {}", key))?;
+                    set_once(&mut baseline_range, &key, value)?;
+                }
+                "offset" | "limit" => anyhow::bail!("unexpected {} in a compare query", key),
+                _ => rest.push((key, value)),
+            }
+        }
+
+        let current = AggregatesQuery::parse(rest, max_buckets)?;
+        // Buckets of distinct products do not add up to the distinct products of the window.
+        anyhow::ensure!(
+            !current.aggregates.contains(&Aggregate::DistinctProducts),
+            "{} cannot be compared",
+            Aggregate::DistinctProducts
+        );
+
+        let mut baseline_range: BucketsRange =
+            baseline_range.context("missing baseline_time_range")?;
+        if current.time_range.inclusive_end() {
+            baseline_range = baseline_range.with_inclusive_end();
+        }
+        anyhow::ensure!(
+            baseline_range.buckets_count() == current.time_range.buckets_count(),
+            "time_range and baseline_time_range differ in length"
+        );
+
+        let baseline = AggregatesQuery {
+            time_range: baseline_range,
+            ..current.clone()
+        };
+
+        Ok(Self { current, baseline })
+    }
+
+    pub fn make_reply(
+        &self,
+        current: &AggregatesReply,
+        baseline: &AggregatesReply,
+    ) -> anyhow::Result<CompareReply> {
+        let metrics = self
+            .current
+            .aggregates
+            .iter()
+            .map(|aggr| {
+                Ok(MetricDelta::new(
+                    *aggr,
+                    total(current, *aggr)?,
+                    total(baseline, *aggr)?,
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(CompareReply {
+            time_range: format_range(&self.current.time_range),
+            baseline_time_range: format_range(&self.baseline.time_range),
+            metrics,
+        })
+    }
+}
+
+fn total(reply: &AggregatesReply, aggr: Aggregate) -> anyhow::Result<u64> {
+    reply
+        .rows
+        .iter()
+        .map(|row| row.value(aggr).map(|value| value as u64))
+        .sum::<Option<u64>>()
+        .with_context(|| format!("row does not contain {}", aggr))
+}
+
+fn format_range(range: &BucketsRange) -> String {
+    format!(
+        "{}_{}",
+        range.from().format(FORMAT_STR_SECONDS),
+        range.to().format(FORMAT_STR_SECONDS)
+    )
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct MetricDelta {
+    pub aggregate: Aggregate,
+    pub current: u64,
+    pub baseline: u64,
+    pub change: i64,
+    // Relative to the baseline, `None` (serialized as null) when the baseline is 0.
+    pub percent_change: Option<f64>,
+}
+
+impl MetricDelta {
+    fn new(aggregate: Aggregate, current: u64, baseline: u64) -> Self {
+        let change = current as i64 - baseline as i64;
+        let percent_change = (baseline > 0).then(|| change as f64 * 100.0 / baseline as f64);
+
+        Self {
+            aggregate,
+            current,
+            baseline,
+            change,
+            percent_change,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CompareReply {
+    pub time_range: String,
+    pub baseline_time_range: String,
+    pub metrics: Vec<MetricDelta>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{aggregates::AggregatesRow, test_utils::make_pairs, time_range::MAX_RANGE_BUCKETS};
+
+    fn make_rows(rows: &[(usize, usize)]) -> Vec<AggregatesRow> {
+        rows.iter()
+            .map(|(count, sum_price)| AggregatesRow {
+                count: Some(*count),
+                sum_price: Some(*sum_price),
+                distinct_products: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deltas() {
+        let query = CompareQuery::from_pairs(
+            make_pairs(&[
+                ("time_range", "2022-03-22T12:20:00_2022-03-22T12:22:00"),
+                (
+                    "baseline_time_range",
+                    "2022-03-22T12:18:00_2022-03-22T12:20:00",
+                ),
+                ("action", "BUY"),
+                ("origin", "origin"),
+                ("aggregates", "COUNT"),
+                ("aggregates", "SUM_PRICE"),
+            ]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap();
+        assert_eq!(query.baseline.origin.as_deref(), Some("origin"));
+
+        let current = query
+            .current
+            .clone()
+            .make_reply(make_rows(&[(3, 300), (3, 150)]))
+            .unwrap();
+        let baseline = query
+            .baseline
+            .clone()
+            .make_reply(make_rows(&[(2, 0), (2, 0)]))
+            .unwrap();
+        let reply = query.make_reply(&current, &baseline).unwrap();
+
+        assert_eq!(reply.time_range, "2022-03-22T12:20:00_2022-03-22T12:22:00");
+        assert_eq!(
+            reply.baseline_time_range,
+            "2022-03-22T12:18:00_2022-03-22T12:20:00"
+        );
+        assert_eq!(
+            reply.metrics,
+            [
+                MetricDelta {
+                    aggregate: Aggregate::Count,
+                    current: 6,
+                    baseline: 4,
+                    change: 2,
+                    percent_change: Some(50.0),
+                },
+                MetricDelta {
+                    aggregate: Aggregate::SumPrice,
+                    current: 450,
+                    baseline: 0,
+                    change: 450,
+                    percent_change: None,
+                },
+            ]
+        );
+
+        let reply = query.make_reply(&baseline, &current).unwrap();
+        assert_eq!(reply.metrics[0].change, -2);
+        assert_eq!(reply.metrics[0].percent_change, Some(-100.0 / 3.0));
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert_eq!(serialized["metrics"][1]["percent_change"], -100.0);
+    }
+
+    #[test]
+    fn invalid_queries() {
+        let pairs = |overrides: &[(&str, &str)]| {
+            let mut pairs = vec![
+                ("time_range", "2022-03-22T12:20:00_2022-03-22T12:22:00"),
+                (
+                    "baseline_time_range",
+                    "2022-03-22T12:18:00_2022-03-22T12:20:00",
+                ),
+                ("action", "BUY"),
+                ("aggregates", "COUNT"),
+            ];
+            pairs.extend_from_slice(overrides);
+            make_pairs(&pairs)
+        };
+
+        CompareQuery::from_pairs(pairs(&[]), MAX_RANGE_BUCKETS).unwrap();
+        CompareQuery::from_pairs(pairs(&[("limit", "1")]), MAX_RANGE_BUCKETS).unwrap_err();
+        CompareQuery::from_pairs(
+            pairs(&[("aggregates", "DISTINCT_PRODUCTS")]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+        CompareQuery::from_pairs(
+            pairs(&[(
+                "baseline_time_range",
+                "2022-03-22T12:10:00_2022-03-22T12:11:00",
+            )]),
+            MAX_RANGE_BUCKETS,
+        )
+        .unwrap_err();
+
+        let mut unequal = pairs(&[]);
+        unequal[1].1 = "2022-03-22T12:10:00_2022-03-22T12:11:00".into();
+        CompareQuery::from_pairs(unequal, MAX_RANGE_BUCKETS).unwrap_err();
+
+        let mut missing = pairs(&[]);
+        missing.remove(1);
+        CompareQuery::from_pairs(missing, MAX_RANGE_BUCKETS).unwrap_err();
+    }
+}
@@ -1,17 +1,66 @@
 use event_queue::producer::EventProducer;
 
-use crate::user_tag::UserTag;
+use crate::{
+    circuit_breaker::{BreakerConfig, CircuitBreaker},
+    dimensions::Dimensions,
+    stats::AppStats,
+    user_tag::UserTag,
+};
 
 pub struct App {
     producer: EventProducer,
+    // Around sending to Kafka, so requests fail fast while the brokers are unreachable.
+    breaker: Option<CircuitBreaker>,
+    // Filled from the topic, see `Dimensions::stream`.
+    dimensions: Dimensions,
+    stats: AppStats,
 }
 
 impl App {
     pub fn new(producer: EventProducer) -> Self {
-        Self { producer }
+        Self {
+            producer,
+            breaker: None,
+            dimensions: Default::default(),
+            stats: Default::default(),
+        }
+    }
+
+    pub fn with_circuit_breaker(mut self, config: BreakerConfig) -> Self {
+        self.breaker = Some(CircuitBreaker::new(config));
+        self
+    }
+
+    pub fn dimensions(&self) -> &Dimensions {
+        &self.dimensions
+    }
+
+    pub fn stats(&self) -> &AppStats {
+        &self.stats
     }
 
     pub async fn send_tag(&self, tag: &UserTag) -> anyhow::Result<()> {
-        self.producer.produce(tag).await
+        let produce = || self.producer.produce_keyed(tag, UserTag::partition_key);
+        match &self.breaker {
+            Some(breaker) => breaker.call(produce).await?,
+            None => produce().await?,
+        }
+        self.stats.tag_ingested();
+        Ok(())
+    }
+
+    // Tags not yet sent when one fails are dropped, so a failed batch may be partially ingested.
+    pub async fn send_tags(&self, tags: &[UserTag]) -> anyhow::Result<()> {
+        let keyed = tags
+            .iter()
+            .map(|tag| (tag.partition_key(), tag))
+            .collect::<Vec<_>>();
+        let produce = || self.producer.produce_many(&keyed);
+        match &self.breaker {
+            Some(breaker) => breaker.call(produce).await?,
+            None => produce().await?,
+        }
+        self.stats.tags_ingested(tags.len() as u64);
+        Ok(())
     }
 }
@@ -0,0 +1,158 @@
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerConfig {
+    // Consecutive failures opening the breaker.
+    pub failure_threshold: u32,
+    // How long an open breaker fails calls right away before letting a probe through.
+    pub cooldown: Duration,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // A single probe call is in flight, others fail right away. Another probe is let through
+    // after `until`, in case this one never completes (e.g. its request was cancelled).
+    HalfOpen { until: Instant },
+}
+
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    fn acquire(&self, now: Instant) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } | State::HalfOpen { until } if now >= until => {
+                *state = State::HalfOpen {
+                    until: now + self.config.cooldown,
+                };
+                Ok(())
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                anyhow::bail!("circuit breaker is open")
+            }
+        }
+    }
+
+    fn record(&self, success: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        *state = match (*state, success) {
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.config.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::Open { until }, false) => State::Open { until },
+            (_, false) => {
+                log::warn!(
+                    "Circuit breaker opened for {} ms",
+                    self.config.cooldown.as_millis()
+                );
+                State::Open {
+                    until: now + self.config.cooldown,
+                }
+            }
+        };
+    }
+
+    // Fails right away while the breaker is open.
+    pub async fn call<T, F, Fut>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        self.acquire(Instant::now())?;
+        let res = f().await;
+        self.record(res.is_ok(), Instant::now());
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(10),
+        });
+        let start = Instant::now();
+        let state = || *breaker.state.lock().unwrap();
+
+        breaker.acquire(start).unwrap();
+        breaker.record(false, start);
+        breaker.record(true, start);
+        breaker.record(false, start);
+        assert_eq!(state(), State::Closed { failures: 1 });
+
+        breaker.record(false, start);
+        let until = start + Duration::from_secs(10);
+        assert_eq!(state(), State::Open { until });
+        breaker.acquire(start + Duration::from_secs(5)).unwrap_err();
+
+        // A failed probe opens the breaker for another cooldown.
+        breaker.acquire(until).unwrap();
+        assert_eq!(
+            state(),
+            State::HalfOpen {
+                until: until + Duration::from_secs(10)
+            }
+        );
+        breaker.acquire(until).unwrap_err();
+        breaker.record(false, until);
+        assert_eq!(
+            state(),
+            State::Open {
+                until: until + Duration::from_secs(10)
+            }
+        );
+
+        let later = until + Duration::from_secs(10);
+        breaker.acquire(later).unwrap();
+        breaker.record(true, later);
+        assert_eq!(state(), State::Closed { failures: 0 });
+        breaker.acquire(later).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_fast() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(60),
+        });
+
+        let res: anyhow::Result<()> = breaker
+            .call(|| async { anyhow::bail!("unreachable") })
+            .await;
+        assert_eq!(res.unwrap_err().to_string(), "unreachable");
+
+        let mut called = false;
+        let res = breaker
+            .call(|| {
+                called = true;
+                async { anyhow::Ok(()) }
+            })
+            .await;
+        assert_eq!(res.unwrap_err().to_string(), "circuit breaker is open");
+        assert!(!called);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::user_tag::UserTag;
+use async_trait::async_trait;
+use event_queue::{
+    codec::Codec,
+    consumer::{EventProcessor, EventStream, OffsetReset, StreamConfig},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::Mutex,
+};
+
+pub const MAX_VALUES_PER_DIMENSION: usize = 10_000;
+
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct DimensionsReply {
+    pub origins: Vec<String>,
+    pub brand_ids: Vec<String>,
+    pub category_ids: Vec<String>,
+}
+
+#[derive(Default)]
+struct ObservedValues {
+    origins: BTreeSet<String>,
+    brand_ids: BTreeSet<String>,
+    category_ids: BTreeSet<String>,
+}
+
+// Values of the tags in the topic, as read by `stream`. Every instance reads the whole topic, so
+// instances agree up to their consumer lag, and a restarted one reads the retained tags again.
+// Once a dimension holds `max_values` values, new ones are ignored until restart.
+pub struct Dimensions {
+    observed: Mutex<ObservedValues>,
+    max_values: usize,
+}
+
+impl Default for Dimensions {
+    fn default() -> Self {
+        Self::new(MAX_VALUES_PER_DIMENSION)
+    }
+}
+
+impl Dimensions {
+    pub fn new(max_values: usize) -> Self {
+        Self {
+            observed: Default::default(),
+            max_values,
+        }
+    }
+
+    fn insert(values: &mut BTreeSet<String>, value: &str, max_values: usize) {
+        if values.len() < max_values && !values.contains(value) {
+            values.insert(value.to_string());
+        }
+    }
+
+    pub fn observe(&self, tag: &UserTag) {
+        let mut observed = self.observed.lock().unwrap();
+        Self::insert(&mut observed.origins, &tag.origin, self.max_values);
+        Self::insert(
+            &mut observed.brand_ids,
+            &tag.product_info.brand_id,
+            self.max_values,
+        );
+        Self::insert(
+            &mut observed.category_ids,
+            &tag.product_info.category_id,
+            self.max_values,
+        );
+    }
+
+    // A stream of all tags in the topic for `Dimensions::process`. Its consumer group is unique to
+    // this instance and never commits, so it starts from the earliest retained tag every time.
+    pub fn stream(
+        servers: &[SocketAddr],
+        topic: String,
+        codec: Codec,
+    ) -> anyhow::Result<EventStream> {
+        let group = format!("dimensions-{}-{:016x}", topic, rand::random::<u64>());
+        let config = StreamConfig {
+            overrides: HashMap::from([("enable.auto.commit".into(), "false".into())]),
+            codec,
+            offset_reset: OffsetReset::Earliest,
+            ..Default::default()
+        };
+        EventStream::new(servers, group, topic, &config)
+    }
+
+    pub fn snapshot(&self) -> DimensionsReply {
+        let observed = self.observed.lock().unwrap();
+        DimensionsReply {
+            origins: observed.origins.iter().cloned().collect(),
+            brand_ids: observed.brand_ids.iter().cloned().collect(),
+            category_ids: observed.category_ids.iter().cloned().collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventProcessor for Dimensions {
+    type Event = UserTag;
+
+    async fn process(&self, tag: UserTag) -> anyhow::Result<()> {
+        self.observe(&tag);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils;
+
+    fn make_tag(origin: &str, brand_id: &str, category_id: &str) -> UserTag {
+        let mut tag = UserTag {
+            origin: origin.into(),
+            ..test_utils::sample_tag()
+        };
+        tag.product_info.brand_id = brand_id.into();
+        tag.product_info.category_id = category_id.into();
+        tag
+    }
+
+    #[test]
+    fn observe_tags() {
+        let dimensions = Dimensions::default();
+        dimensions.observe(&make_tag("origin_b", "brand_a", "category_a"));
+        dimensions.observe(&make_tag("origin_a", "brand_a", "category_b"));
+        dimensions.observe(&make_tag("origin_b", "brand_b", "category_a"));
+
+        let expected = DimensionsReply {
+            origins: vec!["origin_a".into(), "origin_b".into()],
+            brand_ids: vec!["brand_a".into(), "brand_b".into()],
+            category_ids: vec!["category_a".into(), "category_b".into()],
+        };
+        assert_eq!(dimensions.snapshot(), expected);
+    }
+
+    #[test]
+    fn bounded_cardinality() {
+        let dimensions = Dimensions::new(1);
+        dimensions.observe(&make_tag("origin_a", "brand_a", "category_a"));
+        dimensions.observe(&make_tag("origin_b", "brand_b", "category_b"));
+
+        let expected = DimensionsReply {
+            origins: vec!["origin_a".into()],
+            brand_ids: vec!["brand_a".into()],
+            category_ids: vec!["category_a".into()],
+        };
+        assert_eq!(dimensions.snapshot(), expected);
+    }
+}
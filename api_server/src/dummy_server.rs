@@ -1,4 +1,7 @@
-use crate::{aggregates::AggregatesQuery, user_profiles::UserProfilesQuery};
+use crate::{
+    aggregates::AggregatesQuery, server::DEFAULT_MAX_BODY_SIZE, time_range::MAX_RANGE_BUCKETS,
+    user_profiles::UserProfilesQuery,
+};
 use anyhow::Context;
 use std::{net::SocketAddr, str};
 use tokio::sync::oneshot::Receiver;
@@ -12,9 +15,16 @@ pub struct DummyServer {
 
 impl Default for DummyServer {
     fn default() -> Self {
+        Self::new(DEFAULT_MAX_BODY_SIZE)
+    }
+}
+
+impl DummyServer {
+    pub fn new(max_body_size: u64) -> Self {
         let user_tags = warp::path("user_tags")
             .and(warp::path::end())
             .and(warp::post())
+            .and(warp::body::content_length_limit(max_body_size))
             .and(warp::body::bytes())
             .map(|body: Bytes| {
                 let expected = str::from_utf8(body.as_ref());
@@ -31,6 +41,7 @@ impl Default for DummyServer {
             .and(warp::query())
             .and(warp::path::end())
             .and(warp::post())
+            .and(warp::body::content_length_limit(max_body_size))
             .and(warp::body::bytes())
             .map(|cookie: String, query: UserProfilesQuery, body: Bytes| {
                 let expected = str::from_utf8(body.as_ref());
@@ -51,8 +62,17 @@ impl Default for DummyServer {
             .and(warp::query())
             .and(warp::path::end())
             .and(warp::post())
+            .and(warp::body::content_length_limit(max_body_size))
             .and(warp::body::bytes())
-            .map(|query: AggregatesQuery, body: Bytes| {
+            .map(|pairs: Vec<(String, String)>, body: Bytes| {
+                let query = match AggregatesQuery::from_pairs(pairs, MAX_RANGE_BUCKETS) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        log::debug!("Invalid aggregates query: {:?}", e);
+                        return StatusCode::BAD_REQUEST.into_response();
+                    }
+                };
+
                 let expected = str::from_utf8(body.as_ref());
                 log::info!(
                     "Expected response for aggregates with query {:?}: {:?}",
@@ -72,9 +92,7 @@ impl Default for DummyServer {
             filter: filter.boxed(),
         }
     }
-}
 
-impl DummyServer {
     pub async fn run(self, socket: SocketAddr, stop: Receiver<()>) -> anyhow::Result<()> {
         let stop = async move {
             stop.await.ok();
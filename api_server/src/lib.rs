@@ -1,6 +1,16 @@
 pub mod aggregates;
 pub mod app;
+pub mod circuit_breaker;
+pub mod dimensions;
+pub mod rate_limit;
+pub mod request_id;
 pub mod server;
+pub mod shutdown;
+pub mod stats;
+pub mod tag_batch;
+pub mod tag_stream;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 pub mod time_range;
 pub mod user_profiles;
 pub mod user_tag;
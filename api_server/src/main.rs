@@ -1,10 +1,8 @@
 use anyhow::Context;
+use api_server::shutdown::shutdown_signal;
 use serde::Deserialize;
-use std::{net::SocketAddr, process::ExitCode};
-use tokio::{
-    signal,
-    sync::oneshot::{self, Receiver},
-};
+use std::{io::Write, net::SocketAddr, process::ExitCode};
+use tokio::sync::oneshot::{self, Receiver};
 
 #[cfg(not(feature = "only_echo"))]
 #[derive(Deserialize, Debug)]
@@ -12,26 +10,252 @@ struct Args {
     address: SocketAddr,
     kafka_brokers: Vec<SocketAddr>,
     kafka_topic: String,
+    #[serde(default)]
+    aggregates_by_product: bool,
+    #[serde(default = "default_max_body_size")]
+    max_body_size: u64,
+    #[serde(default)]
+    kafka_codec: event_queue::codec::Codec,
+    kafka_acks: Option<event_queue::producer::Acks>,
+    kafka_idempotence: Option<bool>,
+    #[serde(default = "default_max_buckets")]
+    max_buckets: usize,
+    #[serde(default = "default_max_range_buckets")]
+    max_range_buckets: usize,
+    #[serde(default = "default_max_concurrent_requests")]
+    max_concurrent_requests: usize,
+    #[serde(default = "default_max_batch_tags")]
+    max_batch_tags: usize,
+    #[serde(default = "default_max_stream_line_len")]
+    max_stream_line_len: usize,
+    #[serde(default = "default_max_stream_duration_ms")]
+    max_stream_duration_ms: u64,
+    #[serde(default)]
+    tag_reply: api_server::server::TagReply,
+    slow_query_threshold_ms: Option<u64>,
+    #[serde(default)]
+    trace_sample_rate: f64,
+    #[serde(default)]
+    datetime_precision: api_server::user_tag::DatetimePrecision,
+    user_tags_rate_limit: Option<f64>,
+    user_profiles_rate_limit: Option<f64>,
+    aggregates_rate_limit: Option<f64>,
+    rate_limit_header: Option<String>,
+    kafka_breaker_threshold: Option<u32>,
+    #[serde(default = "default_kafka_breaker_cooldown_ms")]
+    kafka_breaker_cooldown_ms: u64,
+    #[serde(default)]
+    kafka_connect_attempts: u32,
+    #[serde(default = "default_kafka_connect_interval_ms")]
+    kafka_connect_interval_ms: u64,
+    tls_cert_path: Option<std::path::PathBuf>,
+    tls_key_path: Option<std::path::PathBuf>,
 }
 
 #[cfg(feature = "only_echo")]
 #[derive(Deserialize, Debug)]
 struct Args {
     address: SocketAddr,
+    #[serde(default = "default_max_body_size")]
+    max_body_size: u64,
+}
+
+#[cfg(not(feature = "only_echo"))]
+impl Args {
+    fn load() -> anyhow::Result<Self> {
+        Self::from_vars(std::env::vars())
+    }
+
+    fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> anyhow::Result<Self> {
+        let args: Self = envy::from_iter(vars)
+            .context("failed to read configuration from environment variables")?;
+        args.validate()?;
+        Ok(args)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.kafka_brokers.is_empty(),
+            "kafka_brokers must not be empty"
+        );
+        anyhow::ensure!(
+            !self.kafka_topic.is_empty(),
+            "kafka_topic must not be empty"
+        );
+        anyhow::ensure!(self.max_body_size > 0, "max_body_size must be positive");
+        anyhow::ensure!(self.max_buckets > 0, "max_buckets must be positive");
+        anyhow::ensure!(
+            self.max_range_buckets >= self.max_buckets,
+            "max_range_buckets must not be smaller than max_buckets"
+        );
+        anyhow::ensure!(
+            self.max_concurrent_requests > 0,
+            "max_concurrent_requests must be positive"
+        );
+        anyhow::ensure!(self.max_batch_tags > 0, "max_batch_tags must be positive");
+        anyhow::ensure!(
+            self.max_stream_line_len > 0,
+            "max_stream_line_len must be positive"
+        );
+        anyhow::ensure!(
+            self.max_stream_duration_ms > 0,
+            "max_stream_duration_ms must be positive"
+        );
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.trace_sample_rate),
+            "trace_sample_rate must be between 0 and 1"
+        );
+        for (name, rate) in [
+            ("user_tags_rate_limit", self.user_tags_rate_limit),
+            ("user_profiles_rate_limit", self.user_profiles_rate_limit),
+            ("aggregates_rate_limit", self.aggregates_rate_limit),
+        ] {
+            anyhow::ensure!(
+                rate.is_none() || rate > Some(0.0),
+                "{} must be positive",
+                name
+            );
+        }
+        anyhow::ensure!(
+            self.kafka_breaker_threshold != Some(0),
+            "kafka_breaker_threshold must be positive"
+        );
+        anyhow::ensure!(
+            self.kafka_connect_attempts == 0 || self.kafka_connect_interval_ms > 0,
+            "kafka_connect_interval_ms must be positive"
+        );
+        Ok(())
+    }
+}
+
+fn default_max_body_size() -> u64 {
+    api_server::server::DEFAULT_MAX_BODY_SIZE
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_max_buckets() -> usize {
+    api_server::server::DEFAULT_MAX_BUCKETS
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_max_batch_tags() -> usize {
+    api_server::tag_batch::DEFAULT_MAX_BATCH_TAGS
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_max_stream_line_len() -> usize {
+    api_server::tag_stream::DEFAULT_MAX_STREAM_LINE_LEN
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_max_stream_duration_ms() -> u64 {
+    api_server::tag_stream::DEFAULT_MAX_STREAM_DURATION.as_millis() as u64
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_kafka_breaker_cooldown_ms() -> u64 {
+    5000
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_kafka_connect_interval_ms() -> u64 {
+    1000
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_max_range_buckets() -> usize {
+    api_server::server::DEFAULT_MAX_RANGE_BUCKETS
+}
+
+#[cfg(not(feature = "only_echo"))]
+fn default_max_concurrent_requests() -> usize {
+    api_server::server::DEFAULT_MAX_CONCURRENT_REQUESTS
 }
 
 #[cfg(not(feature = "only_echo"))]
 async fn run_server(stop: Receiver<()>) -> anyhow::Result<()> {
-    use api_server::{app::App, server::ApiServer};
-    use event_queue::producer::EventProducer;
+    use api_server::{
+        app::App,
+        circuit_breaker::BreakerConfig,
+        dimensions::Dimensions,
+        server::{ApiServer, ServerConfig, TlsConfig},
+    };
+    use event_queue::{
+        producer::{EventProducer, ProducerConfig},
+        retry::{self, RetryConfig},
+    };
+    use std::{sync::Arc, time::Duration};
 
-    let args: Args =
-        envy::from_env().context("failed to read configuration from environment variables")?;
+    let args = Args::load()?;
+
+    let tls = TlsConfig::from_paths(args.tls_cert_path, args.tls_key_path)?;
+
+    let defaults = ProducerConfig::default();
+    let producer_config = ProducerConfig {
+        acks: args.kafka_acks.unwrap_or(defaults.acks),
+        enable_idempotence: args
+            .kafka_idempotence
+            .unwrap_or(defaults.enable_idempotence),
+        codec: args.kafka_codec,
+        ..defaults
+    };
+    let producer = EventProducer::new(
+        &args.kafka_brokers,
+        args.kafka_topic.clone(),
+        &producer_config,
+    )?;
+    let dimensions_stream =
+        Dimensions::stream(&args.kafka_brokers, args.kafka_topic, args.kafka_codec)?;
 
-    let producer = EventProducer::new(&args.kafka_brokers, args.kafka_topic)?;
-    let app = App::new(producer);
+    let retry_config = RetryConfig {
+        attempts: args.kafka_connect_attempts,
+        interval: Duration::from_millis(args.kafka_connect_interval_ms),
+    };
+    if retry_config.attempts > 0 {
+        log::info!("Waiting for the Kafka brokers");
+        tokio::task::block_in_place(|| {
+            retry::retry(&retry_config, || {
+                producer.check_connection(retry_config.interval)
+            })
+        })?;
+    }
+    let mut app = App::new(producer);
+    if let Some(failure_threshold) = args.kafka_breaker_threshold {
+        app = app.with_circuit_breaker(BreakerConfig {
+            failure_threshold,
+            cooldown: Duration::from_millis(args.kafka_breaker_cooldown_ms),
+        });
+    }
 
-    ApiServer::new(app.into()).run(args.address, stop).await
+    let config = ServerConfig {
+        aggregates_by_product: args.aggregates_by_product,
+        max_body_size: args.max_body_size,
+        max_buckets: args.max_buckets,
+        max_range_buckets: args.max_range_buckets,
+        max_concurrent_requests: args.max_concurrent_requests,
+        max_batch_tags: args.max_batch_tags,
+        max_stream_line_len: args.max_stream_line_len,
+        max_stream_duration: Duration::from_millis(args.max_stream_duration_ms),
+        tag_reply: args.tag_reply,
+        datetime_precision: args.datetime_precision,
+        slow_query_threshold: args.slow_query_threshold_ms.map(Duration::from_millis),
+        trace_sample_rate: args.trace_sample_rate,
+        user_tags_rate_limit: args.user_tags_rate_limit,
+        user_profiles_rate_limit: args.user_profiles_rate_limit,
+        aggregates_rate_limit: args.aggregates_rate_limit,
+        rate_limit_header: args.rate_limit_header,
+        tls,
+    };
+
+    let app: Arc<App> = app.into();
+    let app_cloned = app.clone();
+    tokio::spawn(async move {
+        if let Err(e) = dimensions_stream.consume(app_cloned.dimensions()).await {
+            log::error!("Stopped reading dimensions from Kafka: {:?}", e);
+        }
+    });
+
+    ApiServer::new(app, config).run(args.address, stop).await
 }
 
 #[cfg(feature = "only_echo")]
@@ -41,20 +265,40 @@ async fn run_server(stop: Receiver<()>) -> anyhow::Result<()> {
     let args: Args =
         envy::from_env().context("failed to read configuration from environment variables")?;
 
-    DummyServer::default().run(args.address, stop).await
+    DummyServer::new(args.max_body_size)
+        .run(args.address, stop)
+        .await
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    env_logger::init();
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| match api_server::request_id::current() {
+            Some(id) => writeln!(
+                buf,
+                "[{} {} {} request_id={}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                id,
+                record.args()
+            ),
+            None => writeln!(
+                buf,
+                "[{} {} {}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+        })
+        .init();
 
     let (tx, rx) = oneshot::channel();
     let res = tokio::try_join!(
         async move {
-            signal::ctrl_c()
-                .await
-                .context("failed to listen for ctrl-c")?;
-            log::info!("Received a ctrl-c signal");
+            let name = shutdown_signal()?.await?;
+            log::info!("Received {}, shutting down", name);
             tx.send(()).ok();
             Ok(())
         },
@@ -69,3 +313,46 @@ async fn main() -> ExitCode {
         }
     }
 }
+
+#[cfg(all(test, not(feature = "only_echo")))]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn make_vars(overrides: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut vars = HashMap::from([
+            ("address", "127.0.0.1:8080"),
+            ("kafka_brokers", "127.0.0.1:9092"),
+            ("kafka_topic", "user_tags"),
+        ]);
+        vars.extend(overrides.iter().copied());
+        vars.into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_config() {
+        let mut args = Args::from_vars(make_vars(&[])).unwrap();
+        assert_eq!(args.max_buckets, api_server::server::DEFAULT_MAX_BUCKETS);
+
+        args.kafka_brokers.clear();
+        assert!(args.validate().is_err());
+
+        Args::from_vars(make_vars(&[("max_concurrent_requests", "0")])).unwrap_err();
+        Args::from_vars(make_vars(&[("max_buckets", "0")])).unwrap_err();
+        Args::from_vars(make_vars(&[
+            ("max_buckets", "20"),
+            ("max_range_buckets", "10"),
+        ]))
+        .unwrap_err();
+        Args::from_vars(make_vars(&[("kafka_topic", "")])).unwrap_err();
+        Args::from_vars(make_vars(&[("trace_sample_rate", "1.5")])).unwrap_err();
+        Args::from_vars(make_vars(&[("aggregates_rate_limit", "0")])).unwrap_err();
+        Args::from_vars(make_vars(&[("aggregates_rate_limit", "0.5")])).unwrap();
+        Args::from_vars(make_vars(&[("datetime_precision", "minutes")])).unwrap_err();
+        Args::from_vars(make_vars(&[("kafka_breaker_threshold", "0")])).unwrap_err();
+        Args::from_vars(make_vars(&[("max_stream_line_len", "0")])).unwrap_err();
+        Args::from_vars(make_vars(&[("max_stream_duration_ms", "0")])).unwrap_err();
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use warp::{
+    filters::BoxedFilter,
+    http::{HeaderMap, StatusCode},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+// Above this many tracked clients, clients with full buckets are forgotten.
+const MAX_CLIENTS: usize = 100_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket per client, refilled at `per_second` and holding one second worth of requests.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    // Identifies clients when present, e.g. `x-forwarded-for` behind a proxy.
+    header: Option<String>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64, header: Option<String>) -> Self {
+        Self {
+            per_second,
+            burst: per_second.max(1.0),
+            header,
+            buckets: Default::default(),
+        }
+    }
+
+    fn client_key(&self, addr: Option<SocketAddr>, headers: &HeaderMap) -> String {
+        let from_header = self
+            .header
+            .as_deref()
+            .and_then(|name| headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|value| !value.is_empty());
+
+        match (from_header, addr) {
+            (Some(key), _) => key.to_string(),
+            (None, Some(addr)) => addr.ip().to_string(),
+            (None, None) => "unknown".into(),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+    }
+
+    // Takes a token of the client, or returns how long until one is available.
+    fn acquire(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| {
+                self.refill(bucket, now);
+                bucket.tokens < self.burst
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        self.refill(bucket, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Limited {
+    retry_after: Duration,
+}
+
+impl warp::reject::Reject for Limited {}
+
+// Rejects requests of clients over the limit, passes everything through without a limiter.
+pub fn limit(limiter: Option<RateLimiter>) -> BoxedFilter<()> {
+    let limiter = match limiter {
+        Some(limiter) => Arc::new(limiter),
+        None => return warp::any().boxed(),
+    };
+
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .and_then(move |addr: Option<SocketAddr>, headers: HeaderMap| {
+            let key = limiter.client_key(addr, &headers);
+            let res = limiter
+                .acquire(&key, Instant::now())
+                .map_err(|retry_after| {
+                    log::debug!("Rate limited client {}", key);
+                    warp::reject::custom(Limited { retry_after })
+                });
+            async move { res }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+// Turns rejections from `limit` into 429 responses.
+pub fn recover(filter: BoxedFilter<(Response,)>) -> BoxedFilter<(Response,)> {
+    filter
+        .recover(|rejection: Rejection| async move {
+            match rejection.find::<Limited>() {
+                Some(limited) => {
+                    let secs = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                    let response =
+                        warp::reply::with_status(warp::reply(), StatusCode::TOO_MANY_REQUESTS);
+                    let response =
+                        warp::reply::with_header(response, "retry-after", secs.to_string());
+                    Ok(response.into_response())
+                }
+                None => Err(rejection),
+            }
+        })
+        .unify()
+        .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(2.0, None);
+        let start = Instant::now();
+
+        limiter.acquire("a", start).unwrap();
+        limiter.acquire("a", start).unwrap();
+        let retry_after = limiter.acquire("a", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+
+        // Other clients have their own buckets.
+        limiter.acquire("b", start).unwrap();
+
+        limiter
+            .acquire("a", start + Duration::from_millis(500))
+            .unwrap();
+        limiter
+            .acquire("a", start + Duration::from_millis(500))
+            .unwrap_err();
+
+        // Refilled up to the burst only.
+        let later = start + Duration::from_secs(60);
+        limiter.acquire("a", later).unwrap();
+        limiter.acquire("a", later).unwrap();
+        limiter.acquire("a", later).unwrap_err();
+
+        let limiter = RateLimiter::new(0.5, None);
+        limiter.acquire("a", start).unwrap();
+        let retry_after = limiter.acquire("a", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn client_key() {
+        let addr = Some("10.0.0.1:1234".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "192.168.0.1, 10.0.0.2".parse().unwrap());
+
+        let limiter = RateLimiter::new(1.0, None);
+        assert_eq!(limiter.client_key(addr, &headers), "10.0.0.1");
+        assert_eq!(limiter.client_key(None, &headers), "unknown");
+
+        let limiter = RateLimiter::new(1.0, Some("x-forwarded-for".into()));
+        assert_eq!(limiter.client_key(addr, &headers), "192.168.0.1");
+        assert_eq!(limiter.client_key(addr, &HeaderMap::new()), "10.0.0.1");
+    }
+}
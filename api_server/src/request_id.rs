@@ -0,0 +1,82 @@
+use std::future::Future;
+use warp::{http::HeaderValue, reply::Response, Filter, Rejection};
+
+pub const HEADER: &str = "x-request-id";
+
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+fn generate() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+// Takes the id from the request headers if it looks sane, generates a new one otherwise.
+pub fn request_id() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(HEADER)
+        .map(|id: Option<String>| id.filter(|id| is_valid(id)).unwrap_or_else(generate))
+}
+
+// Id of the request being handled by the current task, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+pub async fn scope<F: Future<Output = Response>>(id: String, f: F) -> Response {
+    let mut response = REQUEST_ID.scope(id.clone(), f).await;
+    attach(&mut response, &id);
+    response
+}
+
+pub fn sync_scope<F: FnOnce() -> Response>(id: String, f: F) -> Response {
+    let mut response = REQUEST_ID.sync_scope(id.clone(), f);
+    attach(&mut response, &id);
+    response
+}
+
+fn attach(response: &mut Response, id: &str) {
+    let value = HeaderValue::from_str(id).expect("request id should be a valid header value");
+    response.headers_mut().insert(HEADER, value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        assert!(is_valid("0123abcd-ef.45_67"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("with space"));
+        assert!(!is_valid(&"a".repeat(MAX_LEN + 1)));
+        assert!(is_valid(&generate()));
+    }
+
+    #[tokio::test]
+    async fn current_in_scope() {
+        assert_eq!(current(), None);
+
+        let response = scope("id".into(), async {
+            assert_eq!(current().as_deref(), Some("id"));
+            Response::default()
+        })
+        .await;
+        assert_eq!(response.headers()[HEADER], "id");
+
+        let response = sync_scope("other".into(), || {
+            assert_eq!(current().as_deref(), Some("other"));
+            Response::default()
+        });
+        assert_eq!(response.headers()[HEADER], "other");
+    }
+}
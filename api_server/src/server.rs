@@ -1,32 +1,580 @@
 use crate::{
-    aggregates::{Aggregate, AggregatesQuery, AggregatesRow},
+    aggregates::{compare::CompareQuery, AggregatesQuery},
     app::App,
-    user_profiles::{UserProfilesQuery, UserProfilesReply},
-    user_tag::UserTag,
+    rate_limit::{self, RateLimiter},
+    request_id::{self, request_id},
+    tag_batch::{self, BatchError},
+    tag_stream::{self, StreamLimits},
+    user_profiles::{ProfileCounts, ProfileCountsQuery, UserProfilesQuery, UserProfilesReply},
+    user_tag::{self, DatetimePrecision, UserTag},
 };
 use anyhow::Context;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::oneshot::Receiver;
-use warp::{filters::BoxedFilter, http::StatusCode, reply::Response, Filter, Reply};
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot::Receiver, Semaphore};
+use warp::{
+    filters::BoxedFilter, http::StatusCode, hyper::body::Bytes, hyper::body::HttpBody,
+    reply::Response, Filter, Rejection, Reply,
+};
+
+const GZIP_MIN_SIZE: usize = 1024;
+
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|encoding| {
+        let mut params = encoding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let disabled = params
+            .any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !disabled
+    })
+}
+
+#[derive(Deserialize)]
+struct PrettyFlag {
+    #[serde(default)]
+    pretty: bool,
+}
+
+// Whether the reply should be indented, for debugging with `?pretty=true`.
+fn pretty() -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::query::<PrettyFlag>().map(|flag: PrettyFlag| flag.pretty)
+}
+
+// The whole query string, empty if there is none.
+fn raw_query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::query::raw().or(warp::any().map(String::new)).unify()
+}
+
+// Requests without `content-length` are taken as having an empty body instead of being rejected,
+// unless they carry a body of unknown length (e.g. chunked), which is rejected with 411.
+fn optional_body(limit: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    let without_body = warp::header::optional::<String>("content-length")
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and_then(
+            |length: Option<String>, encoding: Option<String>| async move {
+                match (length, encoding) {
+                    (None, None) => Ok(Bytes::new()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            },
+        );
+
+    warp::body::content_length_limit(limit)
+        .and(warp::body::bytes())
+        .or(without_body)
+        .unify()
+}
+
+fn json_response<T: Serialize>(value: &T, pretty: bool, accept_encoding: Option<&str>) -> Response {
+    let body = if pretty {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    }
+    .expect("serialization to memory buffer failed");
+
+    let gzip = body.len() >= GZIP_MIN_SIZE
+        && matches!(accept_encoding, Some(accept_encoding) if accepts_gzip(accept_encoding));
+    if !gzip {
+        let response = warp::reply::with_status(body, StatusCode::OK);
+        let response = warp::reply::with_header(response, "content-type", "application/json");
+        return response.into_response();
+    }
+
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::fast());
+    encoder
+        .write_all(&body)
+        .expect("compression to memory buffer failed");
+    let compressed = encoder
+        .finish()
+        .expect("compression to memory buffer failed");
+
+    let response = warp::reply::with_status(compressed, StatusCode::OK);
+    let response = warp::reply::with_header(response, "content-type", "application/json");
+    let response = warp::reply::with_header(response, "content-encoding", "gzip");
+    response.into_response()
+}
+
+#[derive(Serialize)]
+struct ErrorReply {
+    error: String,
+    retriable: bool,
+}
+
+fn error_response(status: StatusCode, error: String, retriable: bool) -> Response {
+    let response = warp::reply::json(&ErrorReply { error, retriable });
+    warp::reply::with_status(response, status).into_response()
+}
+
+// The request itself was fine, retrying it later may succeed.
+fn backend_error(e: &anyhow::Error) -> Response {
+    error_response(StatusCode::SERVICE_UNAVAILABLE, format!("{:#}", e), true)
+}
+
+// A bug or inconsistent data on our side, logged since the client cannot do anything about it.
+fn internal_error(e: &anyhow::Error) -> Response {
+    log::error!("Internal error: {:?}", e);
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e), false)
+}
+
+// The request body could not be read, most likely the client went away before sending all of it.
+fn transport_error(e: &anyhow::Error) -> Response {
+    log::warn!("Failed to read the request body: {:?}", e);
+    StatusCode::BAD_REQUEST.into_response()
+}
+
+fn client_error(e: &anyhow::Error) -> Response {
+    error_response(StatusCode::BAD_REQUEST, format!("{:#}", e), false)
+}
+
+fn query_string(pairs: &[(String, String)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn slow_query_message(
+    threshold: Option<Duration>,
+    route: &str,
+    params: &str,
+    elapsed: Duration,
+) -> Option<String> {
+    match threshold {
+        Some(threshold) if elapsed > threshold => Some(format!(
+            "Slow query route=\"{}\" elapsed_ms={} params=\"{}\"",
+            route,
+            elapsed.as_millis(),
+            params
+        )),
+        _ => None,
+    }
+}
+
+fn is_sampled(rate: f64, draw: f64) -> bool {
+    draw < rate
+}
+
+// Runs a query handler, logging it if it is slow or sampled for tracing. The sampling decision
+// is made once per request.
+fn observe_query(
+    config: &ServerConfig,
+    route: &str,
+    params: &str,
+    f: impl FnOnce() -> Response,
+) -> Response {
+    let sampled = is_sampled(config.trace_sample_rate, rand::random());
+    let start = Instant::now();
+    let response = f();
+    let elapsed = start.elapsed();
+
+    if let Some(message) = slow_query_message(config.slow_query_threshold, route, params, elapsed) {
+        log::warn!("{}", message);
+    }
+
+    if sampled {
+        log::info!(
+            "Traced query route=\"{}\" status={} reply_bytes={} elapsed_us={} params=\"{}\"",
+            route,
+            response.status().as_u16(),
+            response.body().size_hint().exact().unwrap_or_default(),
+            elapsed.as_micros(),
+            params
+        );
+    }
+
+    response
+}
+
+// `None` if the query should be rejected with 400.
+// `aggregates` gives the regular query to check, e.g. the current window of a compare query.
+fn check_aggregates_query<Q>(
+    config: &ServerConfig,
+    query: anyhow::Result<Q>,
+    aggregates: impl Fn(&Q) -> &AggregatesQuery,
+) -> Option<Q> {
+    let query = match query {
+        Ok(query) => query,
+        Err(e) => {
+            log::debug!("Invalid aggregates query: {:?}", e);
+            return None;
+        }
+    };
+
+    if aggregates(&query).product_id.is_some() && !config.aggregates_by_product {
+        return None;
+    }
+
+    Some(query)
+}
+
+fn aggregates_response(
+    app: &App,
+    config: &ServerConfig,
+    query: anyhow::Result<AggregatesQuery>,
+    pretty: bool,
+    accept_encoding: Option<&str>,
+) -> Response {
+    let query = match check_aggregates_query(config, query, |query| query) {
+        Some(query) => query,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    // TODO query database for results
+    let response = match query.make_zero_filled_reply(Default::default()) {
+        Ok(response) => response,
+        Err(e) => return internal_error(&e.context("invalid rows read from the database")),
+    };
+    app.stats().aggregates_query();
+    json_response(&response, pretty, accept_encoding)
+}
+
+fn tag_response<T: Serialize>(tag_reply: TagReply, accepted: &T) -> Response {
+    match tag_reply {
+        TagReply::NoContent => StatusCode::NO_CONTENT.into_response(),
+        TagReply::Echo => {
+            warp::reply::with_status(warp::reply::json(accepted), StatusCode::CREATED)
+                .into_response()
+        }
+    }
+}
+
+// Serves ranges longer than `max_buckets` as a series of regular queries.
+fn aggregates_range_response(
+    app: &App,
+    config: &ServerConfig,
+    query: anyhow::Result<AggregatesQuery>,
+    pretty: bool,
+    accept_encoding: Option<&str>,
+) -> Response {
+    let query = match check_aggregates_query(config, query, |query| query) {
+        Some(query) => query,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    // TODO query database for results, the chunks can be read concurrently
+    let merged = query
+        .split(config.max_buckets.max(1))
+        .into_iter()
+        .map(|chunk| chunk.make_zero_filled_reply(Default::default()))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .context("invalid rows read from the database")
+        .and_then(|parts| query.make_merged_reply(parts));
+    let response = match merged {
+        Ok(response) => response,
+        Err(e) => return internal_error(&e),
+    };
+    app.stats().aggregates_query();
+    json_response(&response, pretty, accept_encoding)
+}
+
+fn aggregates_compare_response(
+    app: &App,
+    config: &ServerConfig,
+    query: anyhow::Result<CompareQuery>,
+    pretty: bool,
+    accept_encoding: Option<&str>,
+) -> Response {
+    let query = match check_aggregates_query(config, query, |query| &query.current) {
+        Some(query) => query,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    // TODO query database for results of both windows
+    let replies = query
+        .current
+        .clone()
+        .make_zero_filled_reply(Default::default())
+        .and_then(|current| {
+            let baseline = query
+                .baseline
+                .clone()
+                .make_zero_filled_reply(Default::default())?;
+            Ok((current, baseline))
+        })
+        .context("invalid rows read from the database")
+        .and_then(|(current, baseline)| query.make_reply(&current, &baseline));
+    let response = match replies {
+        Ok(response) => response,
+        Err(e) => return internal_error(&e),
+    };
+    app.stats().aggregates_query();
+    json_response(&response, pretty, accept_encoding)
+}
+
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+pub const DEFAULT_MAX_BUCKETS: usize = 10;
+
+pub const DEFAULT_MAX_RANGE_BUCKETS: usize = 24 * 60;
+
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 1024;
+
+#[derive(Debug)]
+struct Saturated;
+
+impl warp::reject::Reject for Saturated {}
+
+// Requests over the limit are rejected with 503 right away instead of waiting for a permit.
+fn limit_concurrency(
+    filter: BoxedFilter<(Response,)>,
+    permits: Arc<Semaphore>,
+) -> BoxedFilter<(Response,)> {
+    warp::any()
+        .and_then(move || {
+            let permit = permits.clone().try_acquire_owned();
+            async move { permit.map_err(|_| warp::reject::custom(Saturated)) }
+        })
+        .and(filter)
+        .map(|_permit, response: Response| response)
+        .recover(|rejection: Rejection| async move {
+            if rejection.find::<Saturated>().is_some() {
+                log::warn!("Rejected a request, too many requests in flight");
+                Ok(StatusCode::SERVICE_UNAVAILABLE.into_response())
+            } else {
+                Err(rejection)
+            }
+        })
+        .unify()
+        .boxed()
+}
+
+// What a successfully ingested tag is answered with.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TagReply {
+    // 204 without a body, as in the spec.
+    #[default]
+    NoContent,
+    // 201 with the tag as accepted, i.e. with the normalized cookie.
+    Echo,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn from_paths(
+        cert_path: Option<PathBuf>,
+        key_path: Option<PathBuf>,
+    ) -> anyhow::Result<Option<Self>> {
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Ok(Some(Self {
+                cert_path,
+                key_path,
+            })),
+            (None, None) => Ok(None),
+            _ => anyhow::bail!("TLS certificate and key paths must be given together"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub aggregates_by_product: bool,
+    pub max_body_size: u64,
+    pub max_buckets: usize,
+    pub max_range_buckets: usize,
+    pub max_concurrent_requests: usize,
+    pub max_batch_tags: usize,
+    // Of a single user tag in `POST /user_tags/stream`, longer lines are rejected.
+    pub max_stream_line_len: usize,
+    // Of a whole `POST /user_tags/stream` request, the stream is cut off afterwards.
+    pub max_stream_duration: Duration,
+    pub tag_reply: TagReply,
+    // Of datetimes in replies, user tags sent to Kafka always keep milliseconds.
+    pub datetime_precision: DatetimePrecision,
+    // Profile and aggregates queries taking longer are logged, `None` disables the logging.
+    pub slow_query_threshold: Option<Duration>,
+    // Fraction of profile and aggregates queries logged in detail, between 0 and 1.
+    pub trace_sample_rate: f64,
+    // Requests per second of a single client to each group of routes, `None` disables the limit.
+    pub user_tags_rate_limit: Option<f64>,
+    pub user_profiles_rate_limit: Option<f64>,
+    pub aggregates_rate_limit: Option<f64>,
+    // Header identifying rate limited clients, their remote address is used when `None`.
+    pub rate_limit_header: Option<String>,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            aggregates_by_product: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_buckets: DEFAULT_MAX_BUCKETS,
+            max_range_buckets: DEFAULT_MAX_RANGE_BUCKETS,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            max_batch_tags: tag_batch::DEFAULT_MAX_BATCH_TAGS,
+            max_stream_line_len: tag_stream::DEFAULT_MAX_STREAM_LINE_LEN,
+            max_stream_duration: tag_stream::DEFAULT_MAX_STREAM_DURATION,
+            tag_reply: Default::default(),
+            datetime_precision: Default::default(),
+            slow_query_threshold: None,
+            trace_sample_rate: 0.0,
+            user_tags_rate_limit: None,
+            user_profiles_rate_limit: None,
+            aggregates_rate_limit: None,
+            rate_limit_header: None,
+            tls: None,
+        }
+    }
+}
 
 pub struct ApiServer {
     filter: BoxedFilter<(Response,)>,
+    tls: Option<TlsConfig>,
 }
 
 impl ApiServer {
-    pub fn new(app: Arc<App>) -> Self {
+    pub fn new(app: Arc<App>, config: ServerConfig) -> Self {
+        let rate_limit = |per_second: Option<f64>| {
+            rate_limit::limit(
+                per_second.map(|rate| RateLimiter::new(rate, config.rate_limit_header.clone())),
+            )
+        };
+        let user_tags_limit = rate_limit(config.user_tags_rate_limit);
+        let user_profiles_limit = rate_limit(config.user_profiles_rate_limit);
+        let aggregates_limit = rate_limit(config.aggregates_rate_limit);
+
+        let app_cloned = app.clone();
+        let dimensions = warp::path("dimensions")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(request_id())
+            .and(pretty())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .map(
+                move |id: String, pretty: bool, accept_encoding: Option<String>| {
+                    request_id::sync_scope(id, || {
+                        let response = app_cloned.dimensions().snapshot();
+                        json_response(&response, pretty, accept_encoding.as_deref())
+                    })
+                },
+            );
+
+        let app_cloned = app.clone();
+        let tag_reply = config.tag_reply;
+        let precision = config.datetime_precision;
         let user_tags = warp::path("user_tags")
             .and(warp::path::end())
             .and(warp::post())
-            .and(warp::body::json())
-            .then(move |user_tag: UserTag| {
-                let app = app.clone();
-                async move {
+            .and(user_tags_limit.clone())
+            .and(request_id())
+            .and(warp::body::content_length_limit(config.max_body_size))
+            .and(warp::body::bytes())
+            .then(move |id: String, body: Bytes| {
+                let app = app_cloned.clone();
+                request_id::scope(id, async move {
+                    let mut user_tag = match UserTag::from_json(&body).context("invalid user tag") {
+                        Ok(user_tag) => user_tag,
+                        Err(e) => {
+                            log::debug!("Rejected user tag: {:?}", e);
+                            return client_error(&e);
+                        }
+                    };
+                    match user_tag::normalize_cookie(&user_tag.cookie) {
+                        Ok(cookie) => user_tag.cookie = cookie.to_string(),
+                        Err(e) => {
+                            log::debug!("Rejected user tag: {:?}", e);
+                            return client_error(&e);
+                        }
+                    }
+
                     match app.send_tag(&user_tag).await {
+                        Ok(()) => tag_response(tag_reply, &user_tag.with_precision(precision)),
+                        Err(e) => {
+                            log::error!("Failed to send user tag to Kafka: {:?}", e);
+                            backend_error(&e)
+                        }
+                    }
+                })
+            });
+
+        let app_cloned = app.clone();
+        let max_batch_tags = config.max_batch_tags;
+        let user_tags_batch = warp::path("user_tags")
+            .and(warp::path("batch"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(user_tags_limit.clone())
+            .and(request_id())
+            .and(warp::body::content_length_limit(config.max_body_size))
+            .and(warp::body::stream())
+            .then(move |id: String, body| {
+                let app = app_cloned.clone();
+                request_id::scope(id, async move {
+                    let tags = match tag_batch::read_batch(body, max_batch_tags).await {
+                        Ok(tags) => tags,
+                        Err(BatchError::TooManyTags(max)) => {
+                            log::debug!("Rejected a batch of more than {} tags", max);
+                            return error_response(
+                                StatusCode::PAYLOAD_TOO_LARGE,
+                                BatchError::TooManyTags(max).to_string(),
+                                false,
+                            );
+                        }
+                        Err(BatchError::Invalid(e)) => {
+                            log::debug!("Rejected a batch of user tags: {:?}", e);
+                            return client_error(&e);
+                        }
+                        Err(BatchError::Read(e)) => return transport_error(&e),
+                    };
+
+                    match app.send_tags(&tags).await {
                         Ok(()) => {
-                            let response = warp::reply::json(&user_tag);
-                            let response =
-                                warp::reply::with_status(response, StatusCode::NO_CONTENT);
+                            let accepted = tags
+                                .iter()
+                                .map(|tag| tag.with_precision(precision))
+                                .collect::<Vec<_>>();
+                            tag_response(tag_reply, &accepted)
+                        }
+                        Err(e) => {
+                            log::error!("Failed to send a batch of user tags to Kafka: {:?}", e);
+                            backend_error(&e)
+                        }
+                    }
+                })
+            });
+
+        let app_cloned = app.clone();
+        let stream_limits = StreamLimits {
+            max_line_len: config.max_stream_line_len,
+            max_duration: config.max_stream_duration,
+        };
+        let user_tags_stream = warp::path("user_tags")
+            .and(warp::path("stream"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(user_tags_limit.clone())
+            .and(request_id())
+            .and(warp::body::stream())
+            .then(move |id: String, body| {
+                let app = app_cloned.clone();
+                request_id::scope(id, async move {
+                    let res = tag_stream::ingest_stream(body, stream_limits, |tag| {
+                        let app = app.clone();
+                        async move { app.send_tag(&tag).await }
+                    })
+                    .await;
+
+                    match res {
+                        Ok(summary) => {
+                            let response = warp::reply::json(&summary);
+                            let response = warp::reply::with_status(response, StatusCode::OK);
                             let response = warp::reply::with_header(
                                 response,
                                 "content-type",
@@ -34,63 +582,261 @@ impl ApiServer {
                             );
                             response.into_response()
                         }
-                        Err(e) => {
-                            log::error!("Failed to send user tag to Kafka: {:?}", e);
-                            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-                        }
+                        Err(e) => transport_error(&e),
                     }
-                }
+                })
             });
 
+        let app_cloned = app.clone();
+        let config_cloned = config.clone();
         let user_profiles = warp::path("user_profiles")
             .and(warp::path::param())
-            .and(warp::query())
+            .and(raw_query())
             .and(warp::path::end())
             .and(warp::post())
-            .map(|cookie: String, _query: UserProfilesQuery| {
-                // TODO query database for results
-
-                let response = UserProfilesReply {
-                    cookie,
-                    views: Default::default(),
-                    buys: Default::default(),
-                };
-                let response = warp::reply::json(&response);
-                let response = warp::reply::with_status(response, StatusCode::OK);
-                let response =
-                    warp::reply::with_header(response, "content-type", "application-json");
-                response.into_response()
-            });
+            .and(user_profiles_limit.clone())
+            .and(request_id())
+            .and(pretty())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(optional_body(config.max_body_size))
+            .map(
+                move |cookie: String,
+                      raw_query: String,
+                      id: String,
+                      pretty: bool,
+                      accept_encoding: Option<String>,
+                      body: Bytes| {
+                    request_id::sync_scope(id, || {
+                        let query = match UserProfilesQuery::from_request(&raw_query, &body) {
+                            Ok(query) => query,
+                            Err(e) => return client_error(&e),
+                        };
+                        let params = format!("cookie={} {:?}", cookie, query);
+                        observe_query(&config_cloned, "POST /user_profiles", &params, || {
+                            let cookie = match user_tag::normalize_cookie(&cookie) {
+                                Ok(cookie) => cookie.to_string(),
+                                Err(e) => return client_error(&e),
+                            };
+
+                            // TODO query database for the profile record
+                            let record = None;
+
+                            app_cloned.stats().profile_read();
+                            match UserProfilesReply::from_record(cookie, record, &query) {
+                                Some(response) => json_response(
+                                    &response.with_precision(config_cloned.datetime_precision),
+                                    pretty,
+                                    accept_encoding.as_deref(),
+                                ),
+                                None => error_response(
+                                    StatusCode::NOT_FOUND,
+                                    "unknown cookie".into(),
+                                    false,
+                                ),
+                            }
+                        })
+                    })
+                },
+            );
+
+        let app_cloned = app.clone();
+        // Not under `/user_profiles`, where any segment is a cookie.
+        let profile_counts = warp::path("profile_counts")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(user_profiles_limit.clone())
+            .and(request_id())
+            .and(pretty())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(warp::body::content_length_limit(config.max_body_size))
+            .and(warp::body::bytes())
+            .map(
+                move |id: String, pretty: bool, accept_encoding: Option<String>, body: Bytes| {
+                    request_id::sync_scope(id, || {
+                        let query = match ProfileCountsQuery::from_json(&body) {
+                            Ok(query) => query,
+                            Err(e) => return client_error(&e),
+                        };
 
+                        // TODO read the profiles of all cookies in a single batch
+                        let response = query
+                            .cookies
+                            .into_iter()
+                            .map(|cookie| {
+                                app_cloned.stats().profile_read();
+                                ProfileCounts::new(cookie, &[], &query.time_range)
+                            })
+                            .collect::<Vec<_>>();
+                        json_response(&response, pretty, accept_encoding.as_deref())
+                    })
+                },
+            );
+
+        let app_cloned = app.clone();
+        let config_cloned = config.clone();
         let aggregates = warp::path("aggregates")
             .and(warp::query())
             .and(warp::path::end())
             .and(warp::post())
-            .map(|query: AggregatesQuery| {
-                // TODO query database for results
-                let sum_price = query
-                    .aggregates()
-                    .contains(&Aggregate::SumPrice)
-                    .then_some(0);
-                let count = query.aggregates().contains(&Aggregate::Count).then_some(0);
-                let rows = (0..query.time_range.buckets_count())
-                    .map(|_| AggregatesRow { sum_price, count })
-                    .collect::<Vec<_>>();
-
-                let response = query
-                    .make_reply(rows)
-                    .expect("invalid rows read from the database");
-                let response = warp::reply::json(&response);
-                let response = warp::reply::with_status(response, StatusCode::OK);
-                let response =
-                    warp::reply::with_header(response, "content-type", "application-json");
-                response.into_response()
+            .and(aggregates_limit.clone())
+            .and(request_id())
+            .and(pretty())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .map(
+                move |pairs: Vec<(String, String)>,
+                      id: String,
+                      pretty: bool,
+                      accept_encoding: Option<String>| {
+                    let params = query_string(&pairs);
+                    request_id::sync_scope(id, || {
+                        observe_query(&config_cloned, "POST /aggregates", &params, || {
+                            aggregates_response(
+                                &app_cloned,
+                                &config_cloned,
+                                AggregatesQuery::from_pairs(pairs, config_cloned.max_buckets),
+                                pretty,
+                                accept_encoding.as_deref(),
+                            )
+                        })
+                    })
+                },
+            );
+
+        let app_cloned = app.clone();
+        let config_cloned = config.clone();
+        let aggregates_bucket = warp::path("aggregates")
+            .and(warp::path("bucket"))
+            .and(warp::query())
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(aggregates_limit.clone())
+            .and(request_id())
+            .and(pretty())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .map(
+                move |pairs: Vec<(String, String)>,
+                      id: String,
+                      pretty: bool,
+                      accept_encoding: Option<String>| {
+                    let params = query_string(&pairs);
+                    request_id::sync_scope(id, || {
+                        observe_query(&config_cloned, "GET /aggregates/bucket", &params, || {
+                            aggregates_response(
+                                &app_cloned,
+                                &config_cloned,
+                                AggregatesQuery::from_bucket_pairs(pairs),
+                                pretty,
+                                accept_encoding.as_deref(),
+                            )
+                        })
+                    })
+                },
+            );
+
+        let app_cloned = app.clone();
+        let config_cloned = config.clone();
+        let aggregates_range = warp::path("aggregates")
+            .and(warp::path("range"))
+            .and(warp::query())
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(aggregates_limit.clone())
+            .and(request_id())
+            .and(pretty())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .map(
+                move |pairs: Vec<(String, String)>,
+                      id: String,
+                      pretty: bool,
+                      accept_encoding: Option<String>| {
+                    let params = query_string(&pairs);
+                    request_id::sync_scope(id, || {
+                        observe_query(&config_cloned, "POST /aggregates/range", &params, || {
+                            aggregates_range_response(
+                                &app_cloned,
+                                &config_cloned,
+                                AggregatesQuery::from_range_pairs(
+                                    pairs,
+                                    config_cloned.max_range_buckets,
+                                ),
+                                pretty,
+                                accept_encoding.as_deref(),
+                            )
+                        })
+                    })
+                },
+            );
+
+        let app_cloned = app.clone();
+        let config_cloned = config.clone();
+        let aggregates_compare = warp::path("aggregates")
+            .and(warp::path("compare"))
+            .and(warp::query())
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(aggregates_limit.clone())
+            .and(request_id())
+            .and(pretty())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .map(
+                move |pairs: Vec<(String, String)>,
+                      id: String,
+                      pretty: bool,
+                      accept_encoding: Option<String>| {
+                    let params = query_string(&pairs);
+                    request_id::sync_scope(id, || {
+                        observe_query(&config_cloned, "POST /aggregates/compare", &params, || {
+                            aggregates_compare_response(
+                                &app_cloned,
+                                &config_cloned,
+                                CompareQuery::from_pairs(pairs, config_cloned.max_buckets),
+                                pretty,
+                                accept_encoding.as_deref(),
+                            )
+                        })
+                    })
+                },
+            );
+
+        let stats = warp::path("stats")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(request_id())
+            .and(pretty())
+            .map(move |id: String, pretty: bool| {
+                request_id::sync_scope(id, || {
+                    let response = app.stats().snapshot();
+                    json_response(&response, pretty, None)
+                })
             });
 
-        let filter = user_tags.or(user_profiles).unify().or(aggregates).unify();
+        let filter = user_tags
+            .or(user_tags_batch)
+            .unify()
+            .or(user_tags_stream)
+            .unify()
+            .or(profile_counts)
+            .unify()
+            .or(user_profiles)
+            .unify()
+            .or(aggregates)
+            .unify()
+            .or(aggregates_bucket)
+            .unify()
+            .or(aggregates_range)
+            .unify()
+            .or(aggregates_compare)
+            .unify()
+            .or(dimensions)
+            .unify()
+            .or(stats)
+            .unify();
+
+        let permits = Arc::new(Semaphore::new(config.max_concurrent_requests));
 
         Self {
-            filter: filter.boxed(),
+            filter: limit_concurrency(rate_limit::recover(filter.boxed()), permits),
+            tls: config.tls,
         }
     }
 
@@ -99,13 +845,821 @@ impl ApiServer {
             stop.await.ok();
         };
 
-        let (socket, fut) = warp::serve(self.filter)
-            .try_bind_with_graceful_shutdown(socket, stop)
-            .context("failed to start the server")?;
-        log::info!("Server listening on socket {}", socket);
+        match self.tls {
+            Some(tls) => {
+                let (socket, fut) = warp::serve(self.filter)
+                    .tls()
+                    .cert_path(tls.cert_path)
+                    .key_path(tls.key_path)
+                    .try_bind_with_graceful_shutdown(socket, stop)
+                    .context("failed to start the server with TLS")?;
+                log::info!("Server listening on socket {} (TLS)", socket);
 
-        fut.await;
+                fut.await;
+            }
+            None => {
+                let (socket, fut) = warp::serve(self.filter)
+                    .try_bind_with_graceful_shutdown(socket, stop)
+                    .context("failed to start the server")?;
+                log::info!("Server listening on socket {}", socket);
+
+                fut.await;
+            }
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aggregates::{Aggregate, AggregatesRow},
+        test_utils,
+        time_range::BucketsRange,
+        user_tag::Action,
+    };
+    use event_queue::{consumer::EventProcessor, producer::EventProducer};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    fn test_server(config: ServerConfig) -> ApiServer {
+        let brokers = ["127.0.0.1:9092".parse().unwrap()];
+        let producer =
+            EventProducer::new(&brokers, "user_tags".into(), &Default::default()).unwrap();
+        ApiServer::new(App::new(producer).into(), config)
+    }
+
+    #[tokio::test]
+    async fn aggregates_query() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:17:00&action=VIEW&origin=origin&aggregates=COUNT&aggregates=SUM_PRICE")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body["columns"],
+            serde_json::json!(["1m_bucket", "action", "origin", "COUNT", "SUM_PRICE"])
+        );
+        let keys = body.as_object().unwrap().keys().collect::<Vec<_>>();
+        assert_eq!(keys, ["columns", "rows"]);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:17:00&action=VIEW&origin=&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit() {
+        let permits = Arc::new(Semaphore::new(1));
+        let filter = limit_concurrency(
+            warp::any().map(|| StatusCode::OK.into_response()).boxed(),
+            permits.clone(),
+        );
+
+        let held = permits.clone().try_acquire_owned().unwrap();
+        let response = warp::test::request().reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        drop(held);
+        let response = warp::test::request().reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limits() {
+        let server = test_server(ServerConfig {
+            user_profiles_rate_limit: Some(1.0),
+            ..Default::default()
+        });
+        let profile_request = |ip: &str| {
+            warp::test::request()
+                .method("POST")
+                .remote_addr(format!("{}:1234", ip).parse().unwrap())
+                .path("/user_profiles/cookie?time_range=2022-03-22T12:15:00.000_2022-03-22T12:30:00.000")
+        };
+
+        let response = profile_request("10.0.0.1").reply(&server.filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = profile_request("10.0.0.1").reply(&server.filter).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "1");
+
+        let response = profile_request("10.0.0.2").reply(&server.filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Other routes are not limited.
+        let response = warp::test::request()
+            .method("POST")
+            .remote_addr("10.0.0.1:1234".parse().unwrap())
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:17:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn max_buckets() {
+        let server = test_server(ServerConfig {
+            max_buckets: 2,
+            ..Default::default()
+        });
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:17:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:18:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn configured_max_buckets() {
+        let server = test_server(ServerConfig {
+            max_buckets: 20,
+            ..Default::default()
+        });
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:30:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["rows"].as_array().unwrap().len(), 15);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:36:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn aggregates_range_query() {
+        let server = test_server(ServerConfig {
+            max_range_buckets: 30,
+            ..Default::default()
+        });
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates/range?time_range=2022-03-22T12:15:00_2022-03-22T12:45:00&action=BUY&aggregates=COUNT&include_zero_filled=true")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let buckets = body["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row[0].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        let expected = (15..45)
+            .map(|minute| format!("2022-03-22T12:{}:00", minute))
+            .collect::<Vec<_>>();
+        assert_eq!(buckets, expected);
+        assert_eq!(body["zero_filled"], 30);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates/range?time_range=2022-03-22T12:15:00_2022-03-22T12:46:00&action=BUY&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn aggregates_compare_query() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates/compare?time_range=2022-03-22T12:20:00_2022-03-22T12:25:00&baseline_time_range=2022-03-22T12:15:00_2022-03-22T12:20:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let expected = serde_json::json!([{
+            "aggregate": "COUNT",
+            "current": 0,
+            "baseline": 0,
+            "change": 0,
+            "percent_change": null,
+        }]);
+        assert_eq!(body["metrics"], expected);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates/compare?time_range=2022-03-22T12:20:00_2022-03-22T12:25:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Aggregates by product are disabled by default.
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates/compare?time_range=2022-03-22T12:20:00_2022-03-22T12:25:00&baseline_time_range=2022-03-22T12:15:00_2022-03-22T12:20:00&action=VIEW&product_id=1&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn aggregates_bucket_query() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/aggregates/bucket?time=2022-03-22T12:15:00&action=BUY&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body["rows"],
+            serde_json::json!([["2022-03-22T12:15:00", "BUY", "0"]])
+        );
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/aggregates/bucket?time=2022-03-22T12:15:30&action=BUY&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn pretty_reply() {
+        let server = test_server(Default::default());
+        let path = "/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:16:00&action=VIEW&aggregates=COUNT";
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("{}&pretty=true", path))
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        assert!(body.starts_with("{\n  \"columns\": ["));
+        let pretty: serde_json::Value = serde_json::from_str(body).unwrap();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.body().contains(&b'\n'));
+        let compact: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(pretty, compact);
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/aggregates?time_range=2022-03-22T12:15:00_2022-03-22T12:17:00&action=VIEW&aggregates=COUNT")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/stats")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let expected = serde_json::json!({
+            "tags_ingested": 0,
+            "profiles_read": 0,
+            "aggregates_queries": 1,
+        });
+        assert_eq!(body, expected);
+    }
+
+    #[tokio::test]
+    async fn unknown_profile() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(
+                "/user_profiles/cookie?time_range=2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+            )
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["views"], serde_json::json!([]));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/user_profiles/cookie?time_range=2022-03-22T12:15:00.000_2022-03-22T12:30:00.000&not_found_if_unknown=true")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn profile_query_in_body() {
+        let server = test_server(Default::default());
+        let path = "/user_profiles/cookie";
+        let query =
+            "time_range=2022-03-22T12:15:00.000_2022-03-22T12:30:00.000&limit=10&action=BUY";
+        let body = r#"{"time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000", "limit": 10, "action": "BUY"}"#;
+
+        let from_query = warp::test::request()
+            .method("POST")
+            .path(&format!("{}?{}", path, query))
+            .reply(&server.filter)
+            .await;
+        assert_eq!(from_query.status(), StatusCode::OK);
+
+        let from_body = warp::test::request()
+            .method("POST")
+            .path(&format!("{}?pretty=false", path))
+            .body(body)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(from_body.status(), StatusCode::OK);
+        assert_eq!(from_body.body(), from_query.body());
+        let reply: serde_json::Value = serde_json::from_slice(from_body.body()).unwrap();
+        assert!(reply.get("views").is_none());
+
+        // Both must be the same query.
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("{}?{}", path, query))
+            .body(body)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), from_query.body());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("{}?{}", path, query))
+            .body(body.replace("BUY", "VIEW"))
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The spec's debug mode sends the expected reply in the body.
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("{}?{}", path, query))
+            .body(r#"{"cookie": "cookie", "views": [], "buys": []}"#)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), from_query.body());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // A body of unknown length.
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("{}?{}", path, query))
+            .header("transfer-encoding", "chunked")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::LENGTH_REQUIRED);
+    }
+
+    #[tokio::test]
+    async fn request_id_header() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/dimensions")
+            .header(request_id::HEADER, "some-request-id")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[request_id::HEADER], "some-request-id");
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/dimensions")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers()[request_id::HEADER].is_empty());
+    }
+
+    #[tokio::test]
+    async fn dimensions_from_topic() {
+        let brokers = ["127.0.0.1:9092".parse().unwrap()];
+        let producer =
+            EventProducer::new(&brokers, "user_tags".into(), &Default::default()).unwrap();
+        let app: Arc<App> = App::new(producer).into();
+        let server = ApiServer::new(app.clone(), Default::default());
+
+        for (origin, brand_id) in [("b", "y"), ("a", "x"), ("b", "x")] {
+            let mut tag = test_utils::sample_tag();
+            tag.origin = origin.into();
+            tag.product_info.brand_id = brand_id.into();
+            app.dimensions().process(tag).await.unwrap();
+        }
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/dimensions")
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "origins": ["a", "b"],
+                "brand_ids": ["x", "y"],
+                "category_ids": ["category"],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn ingestion_errors() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/user_tags")
+            .body(r#"{"cookie": "cookie"}"#)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["retriable"], false);
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid user tag: missing field `time`"));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/user_tags")
+            .body(test_utils::SAMPLE_TAG.replace(
+                r#""cookie":"cookie""#,
+                &format!(r#""cookie":"{}""#, "a".repeat(user_tag::MAX_COOKIE_LEN + 1)),
+            ))
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = backend_error(&anyhow::anyhow!("failed to send message to Kafka"));
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let expected = serde_json::json!({
+            "error": "failed to send message to Kafka",
+            "retriable": true,
+        });
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn slow_queries() {
+        let pairs = vec![
+            ("action".to_string(), "BUY".to_string()),
+            ("aggregates".to_string(), "COUNT".to_string()),
+        ];
+        let params = query_string(&pairs);
+        assert_eq!(params, "action=BUY&aggregates=COUNT");
+
+        let threshold = Some(Duration::from_millis(10));
+        let message = slow_query_message(
+            threshold,
+            "POST /aggregates",
+            &params,
+            Duration::from_millis(25),
+        );
+        assert_eq!(
+            message.as_deref(),
+            Some("Slow query route=\"POST /aggregates\" elapsed_ms=25 params=\"action=BUY&aggregates=COUNT\"")
+        );
+        assert_eq!(
+            slow_query_message(
+                threshold,
+                "POST /aggregates",
+                &params,
+                Duration::from_millis(5)
+            ),
+            None
+        );
+        assert_eq!(
+            slow_query_message(None, "POST /aggregates", &params, Duration::from_secs(60)),
+            None
+        );
+
+        let config = ServerConfig {
+            slow_query_threshold: threshold,
+            trace_sample_rate: 1.0,
+            ..Default::default()
+        };
+        let response = observe_query(&config, "POST /aggregates", &params, || {
+            std::thread::sleep(Duration::from_millis(20));
+            StatusCode::ACCEPTED.into_response()
+        });
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[test]
+    fn trace_sampling() {
+        let draws = 10_000;
+        let sampled = (0..draws)
+            .filter(|_| is_sampled(0.1, rand::random()))
+            .count();
+        assert!((800..1200).contains(&sampled), "{} sampled", sampled);
+
+        assert!((0..draws).all(|_| !is_sampled(0.0, rand::random())));
+        assert!((0..draws).all(|_| is_sampled(1.0, rand::random())));
+    }
+
+    #[tokio::test]
+    async fn profile_counts() {
+        let server = test_server(Default::default());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/profile_counts")
+            .body(r#"{"cookies": ["a", "b"], "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000"}"#)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let expected = serde_json::json!([
+            {"cookie": "a", "views": 0, "buys": 0},
+            {"cookie": "b", "views": 0, "buys": 0},
+        ]);
+        assert_eq!(body, expected);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/profile_counts")
+            .body(r#"{"cookies": ["a"]}"#)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // A cookie named `counts` is a regular profile.
+        let response = warp::test::request()
+            .method("POST")
+            .path(
+                "/user_profiles/counts?time_range=2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+            )
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["cookie"], "counts");
+    }
+
+    #[tokio::test]
+    async fn tag_replies() {
+        let tag = test_utils::sample_tag();
+
+        let response = tag_response(TagReply::NoContent, &tag);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get("content-type").is_none());
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+
+        let response = tag_response(
+            TagReply::Echo,
+            &tag.with_precision(DatetimePrecision::Seconds),
+        );
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["cookie"], "cookie");
+        assert_eq!(body["time"], "2022-03-22T12:15:00Z");
+    }
+
+    #[tokio::test]
+    async fn configured_stream_limits() {
+        let server = test_server(ServerConfig {
+            max_stream_line_len: 10,
+            ..Default::default()
+        });
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/user_tags/stream")
+            .body(test_utils::SAMPLE_TAG)
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"accepted": 0, "rejected": 1}));
+    }
+
+    #[tokio::test]
+    async fn reject_large_batch() {
+        let server = test_server(ServerConfig {
+            max_batch_tags: 2,
+            ..Default::default()
+        });
+        let tag = test_utils::SAMPLE_TAG;
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/user_tags/batch")
+            .body(format!("[{}]", [tag; 3].join(",")))
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"], "more than 2 tags in a batch");
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/user_tags/batch")
+            .body(format!("[{}, {{}}]", tag))
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn reject_large_batch_while_streaming() {
+        let server = test_server(ServerConfig {
+            max_batch_tags: 2,
+            ..Default::default()
+        });
+        let (socket, serving) = warp::serve(server.filter).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(serving);
+
+        // Only the start of the declared body is sent, the reply must not wait for the rest.
+        let tag = test_utils::SAMPLE_TAG;
+        let body = format!("[{}", [tag; 3].join(","));
+        let head = format!(
+            "POST /user_tags/batch HTTP/1.1\r\nhost: localhost\r\ncontent-length: {}\r\n\r\n",
+            body.len() * 10
+        );
+        let mut stream = TcpStream::connect(socket).await.unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body.as_bytes()).await.unwrap();
+
+        let mut reply = vec![0; 1024];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        let status_line = String::from_utf8_lossy(&reply[..read]);
+        assert!(status_line.starts_with("HTTP/1.1 413"), "{}", status_line);
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_body() {
+        let server = test_server(ServerConfig {
+            max_body_size: 16,
+            ..Default::default()
+        });
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/user_tags")
+            .body(vec![b' '; 17])
+            .reply(&server.filter)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn tls_config() {
+        assert_eq!(TlsConfig::from_paths(None, None).unwrap(), None);
+        assert_eq!(
+            TlsConfig::from_paths(Some("cert.pem".into()), Some("key.pem".into())).unwrap(),
+            Some(TlsConfig {
+                cert_path: "cert.pem".into(),
+                key_path: "key.pem".into(),
+            })
+        );
+        assert!(TlsConfig::from_paths(Some("cert.pem".into()), None).is_err());
+        assert!(TlsConfig::from_paths(None, Some("key.pem".into())).is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_missing_certificate() {
+        let server = test_server(ServerConfig {
+            tls: Some(TlsConfig {
+                cert_path: "/nonexistent/cert.pem".into(),
+                key_path: "/nonexistent/key.pem".into(),
+            }),
+            ..Default::default()
+        });
+
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let res = server.run("127.0.0.1:0".parse().unwrap(), rx).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn accept_encoding() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("deflate, gzip;q=1.0, br"));
+        assert!(accepts_gzip("*"));
+        assert!(!accepts_gzip("deflate, br"));
+        assert!(!accepts_gzip("gzip;q=0"));
+    }
+
+    #[tokio::test]
+    async fn compress_large_reply() {
+        let time_range: BucketsRange =
+            serde_json::from_str("\"2022-03-22T12:15:00_2022-03-22T12:25:00\"").unwrap();
+        let query = AggregatesQuery {
+            time_range,
+            action: Action::Buy,
+            origin: Some("some_long_origin_name".into()),
+            brand_id: Some("some_long_brand_name".into()),
+            category_id: Some("some_long_category_name".into()),
+            country: Some("PL".into()),
+            product_id: Some(42),
+            aggregates: vec![Aggregate::Count, Aggregate::SumPrice],
+            offset: None,
+            limit: None,
+            include_query: false,
+            include_zero_filled: false,
+            compact: false,
+            omit_action: false,
+        };
+        let rows = (0..time_range.buckets_count())
+            .map(|i| AggregatesRow {
+                sum_price: Some(i * 1000),
+                count: Some(i),
+                distinct_products: None,
+            })
+            .collect();
+        let reply = query.make_reply(rows).unwrap();
+        let expected = serde_json::to_vec(&reply).unwrap();
+        assert!(expected.len() >= GZIP_MIN_SIZE);
+
+        let response = json_response(&reply, false, Some("gzip, deflate"));
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let mut decompressed = vec![];
+        GzDecoder::new(body.as_ref())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, expected);
+
+        let response = json_response(&reply, false, None);
+        assert!(response.headers().get("content-encoding").is_none());
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(body.as_ref(), expected);
+    }
+
+    #[tokio::test]
+    async fn skip_small_reply() {
+        let reply = UserProfilesReply::new("cookie".into(), Some(vec![]), Some(vec![]), 200);
+
+        let response = json_response(&reply, false, Some("gzip"));
+        assert!(response.headers().get("content-encoding").is_none());
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Context;
+use std::{future::Future, io};
+use tokio::signal;
+
+// Listens for SIGINT and, on Unix, SIGTERM sent by container orchestrators. The handlers are
+// installed right away, the returned future resolves with the name of the first signal received.
+pub fn shutdown_signal() -> anyhow::Result<impl Future<Output = anyhow::Result<&'static str>>> {
+    #[cfg(unix)]
+    let terminate = {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .context("failed to listen for SIGTERM")?;
+        async move { sigterm.recv().await }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<Option<()>>();
+
+    Ok(first_signal(signal::ctrl_c(), terminate))
+}
+
+async fn first_signal<I, T>(interrupt: I, terminate: T) -> anyhow::Result<&'static str>
+where
+    I: Future<Output = io::Result<()>>,
+    T: Future<Output = Option<()>>,
+{
+    tokio::select! {
+        res = interrupt => res.map(|()| "SIGINT").context("failed to listen for ctrl-c"),
+        _ = terminate => Ok("SIGTERM"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::future;
+
+    #[tokio::test]
+    async fn first_signal_name() {
+        let name = first_signal(future::pending(), future::ready(Some(())))
+            .await
+            .unwrap();
+        assert_eq!(name, "SIGTERM");
+
+        let name = first_signal(future::ready(Ok(())), future::pending())
+            .await
+            .unwrap();
+        assert_eq!(name, "SIGINT");
+
+        let error = io::Error::from(io::ErrorKind::Unsupported);
+        first_signal(future::ready(Err(error)), future::pending())
+            .await
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,66 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Serialize, PartialEq, Eq, Default, Debug)]
+pub struct StatsReply {
+    pub tags_ingested: u64,
+    pub profiles_read: u64,
+    pub aggregates_queries: u64,
+}
+
+// Lifetime counters of this process, meant to be read by humans rather than scraped.
+#[derive(Default)]
+pub struct AppStats {
+    tags_ingested: AtomicU64,
+    profiles_read: AtomicU64,
+    aggregates_queries: AtomicU64,
+}
+
+impl AppStats {
+    pub fn tag_ingested(&self) {
+        self.tags_ingested(1);
+    }
+
+    pub fn tags_ingested(&self, count: u64) {
+        self.tags_ingested.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn profile_read(&self) {
+        self.profiles_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn aggregates_query(&self) {
+        self.aggregates_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsReply {
+        StatsReply {
+            tags_ingested: self.tags_ingested.load(Ordering::Relaxed),
+            profiles_read: self.profiles_read.load(Ordering::Relaxed),
+            aggregates_queries: self.aggregates_queries.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn count() {
+        let stats = AppStats::default();
+        assert_eq!(stats.snapshot(), StatsReply::default());
+
+        stats.tag_ingested();
+        stats.tags_ingested(2);
+        stats.profile_read();
+        stats.aggregates_query();
+
+        let expected = StatsReply {
+            tags_ingested: 3,
+            profiles_read: 1,
+            aggregates_queries: 1,
+        };
+        assert_eq!(stats.snapshot(), expected);
+    }
+}
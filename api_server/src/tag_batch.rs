@@ -0,0 +1,248 @@
+use crate::user_tag::{self, UserTag};
+use futures_util::{Stream, StreamExt};
+use std::{
+    error::Error,
+    fmt::{self, Formatter},
+    mem,
+};
+use warp::Buf;
+
+pub const DEFAULT_MAX_BATCH_TAGS: usize = 1000;
+
+#[derive(Debug)]
+pub enum BatchError {
+    TooManyTags(usize),
+    Invalid(anyhow::Error),
+    // The body could not be read, e.g. the client went away.
+    Read(anyhow::Error),
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyTags(max) => write!(f, "more than {} tags in a batch", max),
+            Self::Invalid(e) | Self::Read(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+fn invalid(e: anyhow::Error) -> BatchError {
+    BatchError::Invalid(e.context("invalid batch"))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    BeforeArray,
+    // Right after `[`, where the array may also end.
+    FirstElement,
+    // Right after `,`.
+    NextElement,
+    InElement,
+    AfterArray,
+}
+
+// Reads a JSON array of user tags chunk by chunk. Elements are parsed as soon as they are
+// complete, and the first element over the limit fails the batch before the rest is read.
+pub struct BatchReader {
+    max_tags: usize,
+    state: State,
+    element: Vec<u8>,
+    // Nesting of the current element, outside of its strings.
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    tags: Vec<UserTag>,
+}
+
+impl BatchReader {
+    pub fn new(max_tags: usize) -> Self {
+        Self {
+            max_tags,
+            state: State::BeforeArray,
+            element: Default::default(),
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            tags: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), BatchError> {
+        for &byte in chunk {
+            match self.state {
+                State::BeforeArray | State::AfterArray if byte.is_ascii_whitespace() => {}
+                State::BeforeArray if byte == b'[' => self.state = State::FirstElement,
+                State::BeforeArray => return Err(invalid(anyhow::anyhow!("expected an array"))),
+                State::AfterArray => {
+                    return Err(invalid(anyhow::anyhow!("trailing characters")));
+                }
+                State::FirstElement | State::NextElement if byte.is_ascii_whitespace() => {}
+                State::FirstElement if byte == b']' => self.state = State::AfterArray,
+                State::FirstElement | State::NextElement => {
+                    if self.tags.len() == self.max_tags {
+                        return Err(BatchError::TooManyTags(self.max_tags));
+                    }
+                    self.state = State::InElement;
+                    self.push_element_byte(byte)?;
+                }
+                State::InElement => self.push_element_byte(byte)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn push_element_byte(&mut self, byte: u8) -> Result<(), BatchError> {
+        if self.in_string {
+            match byte {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => self.escaped = true,
+                b'"' => self.in_string = false,
+                _ => {}
+            }
+        } else {
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 0 => self.depth -= 1,
+                b',' | b']' if self.depth == 0 => {
+                    self.state = match byte {
+                        b',' => State::NextElement,
+                        _ => State::AfterArray,
+                    };
+                    return self.parse_element();
+                }
+                _ => {}
+            }
+        }
+
+        self.element.push(byte);
+        Ok(())
+    }
+
+    fn parse_element(&mut self) -> Result<(), BatchError> {
+        let element = mem::take(&mut self.element);
+        let tag = UserTag::from_json(&element)
+            .map_err(|e| invalid(e.context(format!("tag {}", self.tags.len()))))?;
+        self.tags.push(tag);
+        Ok(())
+    }
+
+    // Normalizes the cookies of the tags. Any invalid tag fails the whole batch.
+    pub fn finish(mut self) -> Result<Vec<UserTag>, BatchError> {
+        if self.state != State::AfterArray {
+            return Err(invalid(anyhow::anyhow!("unexpected end of the array")));
+        }
+
+        for (i, tag) in self.tags.iter_mut().enumerate() {
+            let cookie = user_tag::normalize_cookie(&tag.cookie)
+                .map_err(|e| BatchError::Invalid(e.context(format!("tag {}", i))))?;
+            tag.cookie = cookie.to_string();
+        }
+
+        Ok(self.tags)
+    }
+}
+
+// Reads a batch from a request body as it arrives.
+pub async fn read_batch<S, B, E>(body: S, max_tags: usize) -> Result<Vec<UserTag>, BatchError>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Buf,
+    E: 'static + Error + Send + Sync,
+{
+    let mut reader = BatchReader::new(max_tags);
+
+    futures_util::pin_mut!(body);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| BatchError::Read(e.into()))?;
+        while chunk.has_remaining() {
+            let len = chunk.chunk().len();
+            reader.push(chunk.chunk())?;
+            chunk.advance(len);
+        }
+    }
+
+    reader.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SAMPLE_TAG;
+    use futures_util::stream;
+    use std::{io, time::Duration};
+    use warp::hyper::body::Bytes;
+
+    fn make_batch(tags: usize) -> String {
+        let tag = SAMPLE_TAG.replace(r#""cookie":"cookie""#, r#""cookie":" cookie ""#);
+        format!("[{}]", vec![tag; tags].join(","))
+    }
+
+    fn parse(batch: &str, max_tags: usize) -> Result<Vec<UserTag>, BatchError> {
+        let mut reader = BatchReader::new(max_tags);
+        // Byte by byte, so elements and strings are split across chunks.
+        for byte in batch.as_bytes() {
+            reader.push(&[*byte])?;
+        }
+        reader.finish()
+    }
+
+    #[test]
+    fn parse_tags() {
+        let tags = parse(&make_batch(3), 3).unwrap();
+        assert_eq!(tags.len(), 3);
+        assert!(tags.iter().all(|tag| tag.cookie == "cookie"));
+
+        assert!(parse(" [ ] ", 3).unwrap().is_empty());
+
+        let e = parse(&format!("[{}, {{}}]", SAMPLE_TAG), 3).unwrap_err();
+        assert!(e.to_string().starts_with("invalid batch: tag 1:"), "{}", e);
+
+        let e = parse(SAMPLE_TAG, 3).unwrap_err();
+        assert!(matches!(e, BatchError::Invalid(..)));
+
+        let batch = SAMPLE_TAG.replace(r#""cookie":"cookie""#, r#""cookie":" ""#);
+        let e = parse(&format!("[{}]", batch), 3).unwrap_err();
+        assert_eq!(e.to_string(), "tag 0: empty cookie");
+
+        // Brackets and commas within strings.
+        let batch = SAMPLE_TAG.replace(r#""origin":"origin""#, r#""origin":"[\"],{""#);
+        let tags = parse(&format!("[{}]", batch), 3).unwrap();
+        assert_eq!(tags[0].origin, r#"["],{"#);
+
+        for malformed in ["[", "[,]", "[]]", "{}", "[]x"] {
+            let e = parse(malformed, 3).unwrap_err();
+            assert!(matches!(e, BatchError::Invalid(..)), "{}", malformed);
+        }
+        let e = parse(&format!("[{},]", SAMPLE_TAG), 3).unwrap_err();
+        assert!(matches!(e, BatchError::Invalid(..)));
+    }
+
+    #[tokio::test]
+    async fn reject_too_many_tags() {
+        let e = parse(&make_batch(4), 3).unwrap_err();
+        assert!(matches!(e, BatchError::TooManyTags(3)));
+
+        // The body never ends, the batch is rejected as soon as the second tag starts.
+        let chunks = vec![
+            Ok::<_, io::Error>(Bytes::from(format!("[{},", SAMPLE_TAG))),
+            Ok(Bytes::from_static(b"{\"never\": ")),
+        ];
+        let body = stream::iter(chunks).chain(stream::pending());
+        let res = tokio::time::timeout(Duration::from_secs(5), read_batch(body, 1))
+            .await
+            .unwrap();
+        assert!(matches!(res, Err(BatchError::TooManyTags(1))));
+    }
+
+    #[tokio::test]
+    async fn read_error() {
+        let chunks = vec![
+            Ok(Bytes::from_static(b"[")),
+            Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        ];
+        let res = read_batch(stream::iter(chunks), 1).await;
+        assert!(matches!(res, Err(BatchError::Read(..))));
+    }
+}
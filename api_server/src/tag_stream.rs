@@ -0,0 +1,242 @@
+use crate::user_tag::{self, UserTag};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use std::{error::Error, future::Future, mem, time::Duration};
+use tokio::time::{self, Instant};
+use warp::Buf;
+
+pub const DEFAULT_MAX_STREAM_LINE_LEN: usize = 64 * 1024;
+
+pub const DEFAULT_MAX_STREAM_DURATION: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+pub struct StreamLimits {
+    pub max_line_len: usize,
+    pub max_duration: Duration,
+}
+
+impl Default for StreamLimits {
+    fn default() -> Self {
+        Self {
+            max_line_len: DEFAULT_MAX_STREAM_LINE_LEN,
+            max_duration: DEFAULT_MAX_STREAM_DURATION,
+        }
+    }
+}
+
+#[derive(Serialize, Default, PartialEq, Eq, Debug)]
+pub struct StreamSummary {
+    pub accepted: usize,
+    pub rejected: usize,
+}
+
+enum Line {
+    Complete(Vec<u8>),
+    TooLong,
+}
+
+struct LineSplitter {
+    buffer: Vec<u8>,
+    overflowed: bool,
+    max_line_len: usize,
+}
+
+impl LineSplitter {
+    fn new(max_line_len: usize) -> Self {
+        Self {
+            buffer: Default::default(),
+            overflowed: false,
+            max_line_len,
+        }
+    }
+
+    fn push(&mut self, mut chunk: &[u8], lines: &mut Vec<Line>) {
+        while let Some(pos) = chunk.iter().position(|b| *b == b'\n') {
+            self.extend(&chunk[..pos]);
+            lines.push(self.take());
+            chunk = &chunk[pos + 1..];
+        }
+        self.extend(chunk);
+    }
+
+    fn extend(&mut self, bytes: &[u8]) {
+        if self.overflowed {
+            return;
+        }
+
+        if self.buffer.len() + bytes.len() > self.max_line_len {
+            self.overflowed = true;
+            self.buffer.clear();
+        } else {
+            self.buffer.extend_from_slice(bytes);
+        }
+    }
+
+    fn take(&mut self) -> Line {
+        if mem::take(&mut self.overflowed) {
+            Line::TooLong
+        } else {
+            Line::Complete(mem::take(&mut self.buffer))
+        }
+    }
+
+    fn finish(mut self) -> Option<Line> {
+        (self.overflowed || !self.buffer.is_empty()).then(|| self.take())
+    }
+}
+
+// Fails only when the body cannot be read, invalid lines are counted as rejected.
+pub async fn ingest_stream<S, B, E, F, Fut>(
+    body: S,
+    limits: StreamLimits,
+    mut ingest: F,
+) -> anyhow::Result<StreamSummary>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: Buf,
+    E: 'static + Error + Send + Sync,
+    F: FnMut(UserTag) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let deadline = Instant::now() + limits.max_duration;
+    let mut splitter = LineSplitter::new(limits.max_line_len);
+    let mut summary = StreamSummary::default();
+    let mut lines = vec![];
+
+    futures_util::pin_mut!(body);
+    loop {
+        let mut chunk = match time::timeout_at(deadline, body.next()).await {
+            Ok(Some(chunk)) => chunk?,
+            Ok(None) => {
+                lines.extend(splitter.finish());
+                process_lines(&mut lines, &mut summary, &mut ingest).await;
+                break;
+            }
+            Err(..) => {
+                log::warn!("Stream of user tags exceeded its maximum duration");
+                break;
+            }
+        };
+
+        while chunk.has_remaining() {
+            let len = chunk.chunk().len();
+            splitter.push(chunk.chunk(), &mut lines);
+            chunk.advance(len);
+        }
+        process_lines(&mut lines, &mut summary, &mut ingest).await;
+    }
+
+    Ok(summary)
+}
+
+async fn process_lines<F, Fut>(lines: &mut Vec<Line>, summary: &mut StreamSummary, ingest: &mut F)
+where
+    F: FnMut(UserTag) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    for line in lines.drain(..) {
+        let line = match line {
+            Line::Complete(line) => line,
+            Line::TooLong => {
+                log::warn!("Rejected a streamed user tag exceeding the line length limit");
+                summary.rejected += 1;
+                continue;
+            }
+        };
+
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        let tag = match UserTag::from_json(&line) {
+            Ok(mut tag) => match user_tag::normalize_cookie(&tag.cookie) {
+                Ok(cookie) => {
+                    tag.cookie = cookie.to_string();
+                    tag
+                }
+                Err(e) => {
+                    log::warn!("Rejected a streamed user tag: {}", e);
+                    summary.rejected += 1;
+                    continue;
+                }
+            },
+            Err(e) => {
+                log::warn!("Rejected a malformed streamed user tag: {}", e);
+                summary.rejected += 1;
+                continue;
+            }
+        };
+
+        match ingest(tag).await {
+            Ok(()) => summary.accepted += 1,
+            Err(e) => {
+                log::error!("Failed to ingest a streamed user tag: {:?}", e);
+                summary.rejected += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SAMPLE_TAG;
+    use std::io;
+    use warp::hyper::body::Bytes;
+
+    #[tokio::test]
+    async fn ingest_three_lines() {
+        let body = format!("{}\n{{\"invalid\": true}}\n{}", SAMPLE_TAG, SAMPLE_TAG);
+        // Split the body in the middle of a line.
+        let (first, second) = body.split_at(SAMPLE_TAG.len() / 2);
+        let chunks = vec![
+            Ok::<_, io::Error>(Bytes::copy_from_slice(first.as_bytes())),
+            Ok(Bytes::copy_from_slice(second.as_bytes())),
+        ];
+
+        let mut ingested = vec![];
+        let summary = ingest_stream(
+            futures_util::stream::iter(chunks),
+            StreamLimits::default(),
+            |tag| {
+                ingested.push(tag);
+                async { Ok(()) }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            summary,
+            StreamSummary {
+                accepted: 2,
+                rejected: 1
+            }
+        );
+        assert_eq!(ingested.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reject_too_long_line() {
+        let body = format!("{}\n{}\n", SAMPLE_TAG, SAMPLE_TAG.repeat(2));
+        let chunks = vec![Ok::<_, io::Error>(Bytes::from(body))];
+        let limits = StreamLimits {
+            max_line_len: SAMPLE_TAG.len(),
+            ..Default::default()
+        };
+
+        let summary = ingest_stream(futures_util::stream::iter(chunks), limits, |_| async {
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            summary,
+            StreamSummary {
+                accepted: 1,
+                rejected: 1
+            }
+        );
+    }
+}
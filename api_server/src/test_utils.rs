@@ -0,0 +1,44 @@
+// Fixtures shared by the tests of this crate and of the consumer.
+use crate::user_tag::{Action, Device, ProductInfo, UserTag, SCHEMA_VERSION};
+use chrono::{TimeZone, Utc};
+
+// `sample_tag` as sent by clients.
+pub const SAMPLE_TAG: &str = r#"{"time":"2022-03-22T12:15:00.000Z","cookie":"cookie","country":"PL","device":"PC","action":"VIEW","origin":"origin","product_info":{"product_id":1,"brand_id":"brand","category_id":"category","price":100}}"#;
+
+// A view at 2022-03-22T12:15:00, tests override the fields they care about, e.g.
+// `UserTag { action: Action::Buy, ..sample_tag() }`.
+pub fn sample_tag() -> UserTag {
+    UserTag {
+        time: Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap(),
+        cookie: "cookie".into(),
+        country: "PL".into(),
+        device: Device::Pc,
+        action: Action::View,
+        origin: "origin".into(),
+        product_info: ProductInfo {
+            product_id: 1,
+            brand_id: "brand".into(),
+            category_id: "category".into(),
+            price: 100,
+        },
+        schema_version: SCHEMA_VERSION,
+    }
+}
+
+// Query string pairs, e.g. of an aggregates query.
+pub fn make_pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sample_tag_json() {
+        assert_eq!(serde_json::to_string(&sample_tag()).unwrap(), SAMPLE_TAG);
+    }
+}
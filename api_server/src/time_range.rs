@@ -1,6 +1,6 @@
 use chrono::{DateTime, Duration, NaiveDateTime, Timelike, Utc};
 use serde::{
-    de::{self, Unexpected, Visitor},
+    de::{self, IntoDeserializer, Unexpected, Visitor},
     Deserialize, Deserializer,
 };
 use std::fmt::{self, Formatter};
@@ -10,6 +10,8 @@ use std::fmt::{self, Formatter};
 pub struct TimeRange<const BUCKETS: bool> {
     from: DateTime<Utc>,
     to: DateTime<Utc>,
+    // Only buckets ranges may include the bucket starting at `to`.
+    inclusive_end: bool,
 }
 
 impl<const BUCKETS: bool> TimeRange<BUCKETS> {
@@ -20,15 +22,68 @@ impl<const BUCKETS: bool> TimeRange<BUCKETS> {
     pub fn to(&self) -> &DateTime<Utc> {
         &self.to
     }
+
+    // Exclusive end of the range, past the bucket at `to` if it is included.
+    pub fn end(&self) -> DateTime<Utc> {
+        if self.inclusive_end {
+            self.to + Duration::minutes(1)
+        } else {
+            self.to
+        }
+    }
+
+    // Ranges are [from, to) for both profiles and aggregates, as required by the spec. A tag at
+    // `to` belongs to the next range, unless the range includes its end bucket.
+    pub fn contains(&self, time: &DateTime<Utc>) -> bool {
+        self.from <= *time && *time < self.end()
+    }
 }
 
 pub type SimpleTimeRange = TimeRange<false>;
 
 pub type BucketsRange = TimeRange<true>;
 
+// Limit on the length of a deserialized `BucketsRange`.
+pub const MAX_RANGE_BUCKETS: usize = 10;
+
 impl BucketsRange {
+    // Same format as a deserialized range, but up to `max_buckets` long.
+    pub fn parse(v: &str, max_buckets: usize) -> anyhow::Result<Self> {
+        let deserializer: de::value::StrDeserializer<de::value::Error> = v.into_deserializer();
+        let range = deserializer.deserialize_str(TimeRangeVisitor { max_buckets })?;
+        Ok(range)
+    }
+
+    // The same range with the bucket starting at `to` included, one bucket longer.
+    pub fn with_inclusive_end(self) -> Self {
+        Self {
+            inclusive_end: true,
+            ..self
+        }
+    }
+
+    pub fn inclusive_end(&self) -> bool {
+        self.inclusive_end
+    }
+
+    // Consecutive ranges of at most `max_buckets` buckets covering this one, all with exclusive ends.
+    pub fn split(&self, max_buckets: usize) -> impl '_ + Iterator<Item = BucketsRange> {
+        assert!(
+            max_buckets > 0,
+            "ranges must be split into non-empty chunks"
+        );
+        let step = Duration::minutes(max_buckets.try_into().unwrap());
+        self.bucket_starts()
+            .step_by(max_buckets)
+            .map(move |from| BucketsRange {
+                from,
+                to: (from + step).min(self.end()),
+                inclusive_end: false,
+            })
+    }
+
     pub fn buckets_count(&self) -> usize {
-        (self.to - self.from).num_minutes().try_into().unwrap()
+        (self.end() - self.from).num_minutes().try_into().unwrap()
     }
 
     pub fn bucket_starts(&self) -> impl '_ + Iterator<Item = DateTime<Utc>> {
@@ -37,7 +92,9 @@ impl BucketsRange {
     }
 }
 
-struct TimeRangeVisitor<const BUCKETS: bool>;
+struct TimeRangeVisitor<const BUCKETS: bool> {
+    max_buckets: usize,
+}
 
 const FORMAT_STR_MILLIS: &str = "%Y-%m-%dT%H:%M:%S%.3f";
 pub const FORMAT_STR_SECONDS: &str = "%Y-%m-%dT%H:%M:%S";
@@ -46,13 +103,11 @@ impl<'de, const BUCKETS: bool> Visitor<'de> for TimeRangeVisitor<BUCKETS> {
     type Value = TimeRange<BUCKETS>;
 
     fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result where {
-        let msg = if BUCKETS {
-            "a 1-minute bucket range string in format \"2022-03-22T12:15:00_2022-03-22T12:30:00\", maximum 10 minutes"
+        if BUCKETS {
+            write!(f, "a 1-minute bucket range string in format \"2022-03-22T12:15:00_2022-03-22T12:30:00\", maximum {} minutes", self.max_buckets)
         } else {
-            "a time range string in format \"2022-03-22T12:15:00.000_2022-03-22T12:30:00.000\""
-        };
-
-        f.write_str(msg)
+            f.write_str("a time range string in format \"2022-03-22T12:15:00.000_2022-03-22T12:30:00.000\", either bound may be empty")
+        }
     }
 
     fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
@@ -65,33 +120,43 @@ impl<'de, const BUCKETS: bool> Visitor<'de> for TimeRangeVisitor<BUCKETS> {
 
         let mut chunks = v.split('_');
 
+        // Simple ranges may be open-ended, a missing start defaults to the epoch and a missing end
+        // to the current time.
         let v = chunks.next().ok_or_else(make_err)?;
-        let from: NaiveDateTime =
-            NaiveDateTime::parse_from_str(v, format_str).map_err(|_| make_err())?;
+        let from: NaiveDateTime = if !BUCKETS && v.is_empty() {
+            NaiveDateTime::from_timestamp_opt(0, 0).unwrap()
+        } else {
+            NaiveDateTime::parse_from_str(v, format_str).map_err(|_| make_err())?
+        };
         let v = chunks.next().ok_or_else(make_err)?;
-        let to: NaiveDateTime =
-            NaiveDateTime::parse_from_str(v, format_str).map_err(|_| make_err())?;
+        let to: NaiveDateTime = if !BUCKETS && v.is_empty() {
+            Utc::now().naive_utc()
+        } else {
+            NaiveDateTime::parse_from_str(v, format_str).map_err(|_| make_err())?
+        };
 
         if chunks.next().is_some() || from > to {
             return Err(make_err());
         }
 
-        if BUCKETS
-            && (from.second() != 0 || to.second() != 0 || (to - from) > Duration::minutes(10))
-        {
+        let max_duration = Duration::minutes(self.max_buckets.try_into().map_err(|_| make_err())?);
+        if BUCKETS && (from.second() != 0 || to.second() != 0 || (to - from) > max_duration) {
             return Err(make_err());
         }
 
         Ok(Self::Value {
             from: DateTime::from_utc(from, Utc),
             to: DateTime::from_utc(to, Utc),
+            inclusive_end: false,
         })
     }
 }
 
 impl<'de, const BUCKETS: bool> Deserialize<'de> for TimeRange<BUCKETS> {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        deserializer.deserialize_str(TimeRangeVisitor)
+        deserializer.deserialize_str(TimeRangeVisitor {
+            max_buckets: MAX_RANGE_BUCKETS,
+        })
     }
 }
 
@@ -128,6 +193,7 @@ mod test {
         let expected = SimpleTimeRange {
             from: Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap(),
             to: Utc.with_ymd_and_hms(2022, 3, 22, 12, 30, 0).unwrap(),
+            inclusive_end: false,
         };
         let as_str = "\"2022-03-22T12:15:00.000_2022-03-22T12:30:00.000\"";
         let deserialized: SimpleTimeRange = serde_json::from_str(as_str).unwrap();
@@ -136,6 +202,7 @@ mod test {
         let expected = SimpleTimeRange {
             from: Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 12).unwrap(),
             to: Utc.with_ymd_and_hms(2022, 3, 22, 12, 30, 1).unwrap(),
+            inclusive_end: false,
         };
         let as_str = "\"2022-03-22T12:15:12.000_2022-03-22T12:30:01.000\"";
         let deserialized: SimpleTimeRange = serde_json::from_str(as_str).unwrap();
@@ -148,6 +215,43 @@ mod test {
         // More than 2 datetimes.
         let as_str = "\"2022-03-22T12:15:00.000_2022-03-22T12:30:00.000_2022-03-22T12:45:00.000\"";
         serde_json::from_str::<SimpleTimeRange>(as_str).unwrap_err();
+
+        // No separator.
+        let as_str = "\"2022-03-22T12:15:00.000\"";
+        serde_json::from_str::<SimpleTimeRange>(as_str).unwrap_err();
+    }
+
+    #[test]
+    fn de_open_simpletimerange() {
+        let epoch = Utc.timestamp_opt(0, 0).unwrap();
+        let before = Utc::now();
+
+        // Open start.
+        let as_str = "\"_2022-03-22T12:30:00.000\"";
+        let deserialized: SimpleTimeRange = serde_json::from_str(as_str).unwrap();
+        assert_eq!(deserialized.from, epoch);
+        assert_eq!(
+            deserialized.to,
+            Utc.with_ymd_and_hms(2022, 3, 22, 12, 30, 0).unwrap()
+        );
+
+        // Open end.
+        let as_str = "\"2022-03-22T12:15:00.000_\"";
+        let deserialized: SimpleTimeRange = serde_json::from_str(as_str).unwrap();
+        assert_eq!(
+            deserialized.from,
+            Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap()
+        );
+        assert!(deserialized.to >= before && deserialized.to <= Utc::now());
+
+        // Fully open.
+        let deserialized: SimpleTimeRange = serde_json::from_str("\"_\"").unwrap();
+        assert_eq!(deserialized.from, epoch);
+        assert!(deserialized.to >= before && deserialized.to <= Utc::now());
+
+        // Buckets ranges must stay closed.
+        serde_json::from_str::<BucketsRange>("\"_2022-03-22T12:30:00\"").unwrap_err();
+        serde_json::from_str::<BucketsRange>("\"2022-03-22T12:30:00_\"").unwrap_err();
     }
 
     #[test]
@@ -155,6 +259,7 @@ mod test {
         let expected = BucketsRange {
             from: Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap(),
             to: Utc.with_ymd_and_hms(2022, 3, 22, 12, 25, 0).unwrap(),
+            inclusive_end: false,
         };
 
         let as_str = "\"2022-03-22T12:15:00_2022-03-22T12:25:00\"";
@@ -187,6 +292,7 @@ mod test {
         let range = BucketsRange {
             from: Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap(),
             to: Utc.with_ymd_and_hms(2022, 3, 22, 12, 20, 0).unwrap(),
+            inclusive_end: false,
         };
 
         assert_eq!(range.buckets_count(), 5);
@@ -207,6 +313,7 @@ mod test {
         let range = BucketsRange {
             from: Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap(),
             to: Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap(),
+            inclusive_end: false,
         };
 
         assert_eq!(range.buckets_count(), 0);
@@ -218,4 +325,92 @@ mod test {
         let expected: Vec<String> = Default::default();
         assert_eq!(starts, expected);
     }
+
+    #[test]
+    fn boundaries() {
+        let from = Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap();
+        let to = Utc.with_ymd_and_hms(2022, 3, 22, 12, 17, 0).unwrap();
+
+        let simple = SimpleTimeRange {
+            from,
+            to,
+            inclusive_end: false,
+        };
+        assert!(simple.contains(&from));
+        assert!(!simple.contains(&to));
+        assert!(simple.contains(&(to - Duration::milliseconds(1))));
+        assert!(!simple.contains(&(from - Duration::milliseconds(1))));
+
+        let buckets = BucketsRange {
+            from,
+            to,
+            inclusive_end: false,
+        };
+        assert!(buckets.contains(&from));
+        assert!(!buckets.contains(&to));
+        assert_eq!(
+            buckets.bucket_starts().last().unwrap(),
+            to - Duration::minutes(1)
+        );
+
+        let empty = SimpleTimeRange {
+            from,
+            to: from,
+            inclusive_end: false,
+        };
+        assert!(!empty.contains(&from));
+
+        let inclusive = buckets.with_inclusive_end();
+        assert!(inclusive.contains(&to));
+        assert!(!inclusive.contains(&(to + Duration::minutes(1))));
+        assert_eq!(inclusive.bucket_starts().last().unwrap(), to);
+    }
+
+    #[test]
+    fn inclusive_end() {
+        let exclusive = BucketsRange::parse("2022-03-22T12:15:00_2022-03-22T12:20:00", 10).unwrap();
+        let inclusive = exclusive.with_inclusive_end();
+        assert!(!exclusive.inclusive_end());
+        assert_eq!(exclusive.buckets_count(), 5);
+        assert_eq!(inclusive.buckets_count(), 6);
+        assert_eq!(inclusive.bucket_starts().count(), 6);
+        assert_eq!(inclusive.to(), exclusive.to());
+        assert_eq!(inclusive.end(), *exclusive.to() + Duration::minutes(1));
+
+        let chunks = inclusive.split(4).collect::<Vec<_>>();
+        let counts = chunks
+            .iter()
+            .map(BucketsRange::buckets_count)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![4, 2]);
+        assert_eq!(chunks[1].end(), inclusive.end());
+
+        // An empty range still covers its end bucket.
+        let empty = BucketsRange::parse("2022-03-22T12:15:00_2022-03-22T12:15:00", 10).unwrap();
+        assert_eq!(empty.buckets_count(), 0);
+        assert_eq!(empty.with_inclusive_end().buckets_count(), 1);
+    }
+
+    #[test]
+    fn split_long_range() {
+        let range = BucketsRange::parse("2022-03-22T12:15:00_2022-03-22T12:40:00", 60).unwrap();
+        assert_eq!(range.buckets_count(), 25);
+        BucketsRange::parse("2022-03-22T12:15:00_2022-03-22T12:40:00", 20).unwrap_err();
+        BucketsRange::parse("2022-03-22T12:15:30_2022-03-22T12:40:00", 60).unwrap_err();
+
+        let chunks = range.split(10).collect::<Vec<_>>();
+        let counts = chunks
+            .iter()
+            .map(BucketsRange::buckets_count)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![10, 10, 5]);
+        assert_eq!(chunks[0].from(), range.from());
+        assert_eq!(chunks[2].to(), range.to());
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[0].to(), pair[1].from());
+        }
+
+        let empty = BucketsRange::parse("2022-03-22T12:15:00_2022-03-22T12:15:00", 60).unwrap();
+        assert_eq!(empty.split(10).count(), 0);
+    }
 }
@@ -1,22 +1,663 @@
-use crate::{time_range::SimpleTimeRange, user_tag::UserTag};
-use serde::{Deserialize, Serialize};
+use crate::{
+    aggregates::{AggregatesQuery, AggregatesRow},
+    time_range::SimpleTimeRange,
+    user_tag::{self, Action, DatetimePrecision, UserTag},
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, PartialEq, Eq, Debug)]
 pub struct UserProfilesQuery {
     pub time_range: SimpleTimeRange,
     #[serde(default = "UserProfilesQuery::default_limit")]
     pub limit: u32,
+    // Both views and buys when absent.
+    pub action: Option<Action>,
+    // Reply with 404 instead of empty lists for cookies without a profile record.
+    #[serde(default)]
+    pub not_found_if_unknown: bool,
+    // The spec's reply is exactly `{cookie, views, buys}`, `last_seen` is added only on request.
+    #[serde(default)]
+    pub include_last_seen: bool,
 }
 
 impl UserProfilesQuery {
     fn default_limit() -> u32 {
         200
     }
+
+    pub fn includes(&self, action: Action) -> bool {
+        self.action.is_none() || self.action == Some(action)
+    }
+
+    // The query comes either in the query string or as a JSON body with the same fields. When
+    // both are given they must be the same query. A body with other fields is ignored (e.g. the
+    // expected reply sent in the spec's debug mode). Other parameters, like `pretty`, are left
+    // to the query string.
+    pub fn from_request(query: &str, body: &[u8]) -> anyhow::Result<Self> {
+        let pairs: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).context("invalid query string")?;
+        let in_query = pairs
+            .iter()
+            .any(|(key, _)| QUERY_FIELDS.contains(&key.as_str()));
+        let from_body = Self::from_body(body)
+            .transpose()
+            .context("invalid query in the body")?;
+
+        match from_body {
+            Some(from_body) if !in_query => Ok(from_body),
+            from_body => {
+                let from_query: Self =
+                    serde_urlencoded::from_str(query).context("invalid query string")?;
+                if let Some(from_body) = from_body {
+                    anyhow::ensure!(
+                        from_body == from_query,
+                        "the query in the body differs from the query string"
+                    );
+                }
+                Ok(from_query)
+            }
+        }
+    }
+
+    // `None` when the body is not meant as a query: empty, not a JSON object or an object with
+    // other fields.
+    fn from_body(body: &[u8]) -> Option<anyhow::Result<Self>> {
+        let fields: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(body).ok()?;
+        if fields.is_empty()
+            || !fields
+                .keys()
+                .all(|key| QUERY_FIELDS.contains(&key.as_str()))
+        {
+            return None;
+        }
+
+        Some(serde_json::from_value(fields.into()).map_err(anyhow::Error::from))
+    }
 }
 
-#[derive(Serialize)]
+const QUERY_FIELDS: [&str; 5] = [
+    "time_range",
+    "limit",
+    "action",
+    "not_found_if_unknown",
+    "include_last_seen",
+];
+
 pub struct UserProfilesReply {
     pub cookie: String,
-    pub views: Vec<UserTag>,
-    pub buys: Vec<UserTag>,
+    // `None` when excluded by the query's action.
+    pub views: Option<Vec<UserTag>>,
+    pub buys: Option<Vec<UserTag>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+// Orders tags with equal times by all their other fields.
+fn tie_break_key(tag: &UserTag) -> impl '_ + Ord {
+    (
+        &tag.cookie,
+        tag.product_info.product_id,
+        tag.action,
+        &tag.origin,
+        &tag.country,
+        tag.device,
+        &tag.product_info.brand_id,
+        &tag.product_info.category_id,
+        tag.product_info.price,
+        tag.schema_version,
+    )
+}
+
+// Newest first. Ties are broken so that repeated reads return the same order, and truncation
+// keeps the same tags regardless of the order they were stored in.
+fn sort_and_truncate(tags: &mut Vec<UserTag>, limit: usize) {
+    tags.sort_by(|a, b| {
+        b.time
+            .cmp(&a.time)
+            .then_with(|| tie_break_key(a).cmp(&tie_break_key(b)))
+    });
+    tags.truncate(limit);
+}
+
+impl UserProfilesReply {
+    pub fn new(
+        cookie: String,
+        mut views: Option<Vec<UserTag>>,
+        mut buys: Option<Vec<UserTag>>,
+        limit: usize,
+    ) -> Self {
+        for tags in views.iter_mut().chain(buys.iter_mut()) {
+            sort_and_truncate(tags, limit);
+        }
+
+        Self {
+            cookie,
+            views,
+            buys,
+            last_seen: None,
+        }
+    }
+
+    // Sets `last_seen` to the newest time among the returned tags, left out of empty profiles.
+    pub fn with_last_seen(mut self) -> Self {
+        self.last_seen = self
+            .views
+            .iter()
+            .chain(&self.buys)
+            .flatten()
+            .map(|tag| tag.time)
+            .max();
+        self
+    }
+
+    pub fn with_precision(&self, precision: DatetimePrecision) -> ReplyWithPrecision<'_> {
+        ReplyWithPrecision {
+            reply: self,
+            precision,
+        }
+    }
+
+    // `record` holds all stored tags of the cookie, `None` if it has never been seen.
+    // Returns `None` when the query asks to tell unknown cookies apart.
+    pub fn from_record(
+        cookie: String,
+        record: Option<Vec<UserTag>>,
+        query: &UserProfilesQuery,
+    ) -> Option<Self> {
+        if record.is_none() && query.not_found_if_unknown {
+            return None;
+        }
+
+        let (views, buys): (Vec<_>, Vec<_>) = record
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|tag| query.time_range.contains(&tag.time))
+            .partition(|tag| tag.action == Action::View);
+
+        let reply = Self::new(
+            cookie,
+            query.includes(Action::View).then_some(views),
+            query.includes(Action::Buy).then_some(buys),
+            query.limit as usize,
+        );
+        Some(if query.include_last_seen {
+            reply.with_last_seen()
+        } else {
+            reply
+        })
+    }
+
+    // Aggregates of this profile's tags alone, for validating the aggregates pipeline. Stored
+    // aggregates count the tags of all users, so they can only be compared with the sum over
+    // all profiles, and only while no profile was truncated to its limit within the range.
+    pub fn aggregate(&self, query: &AggregatesQuery) -> HashMap<DateTime<Utc>, AggregatesRow> {
+        query.rows_from_tags(self.views.iter().chain(&self.buys).flatten())
+    }
+}
+
+pub const MAX_COUNTS_COOKIES: usize = 100;
+
+#[derive(Deserialize, Debug)]
+pub struct ProfileCountsQuery {
+    pub cookies: Vec<String>,
+    pub time_range: SimpleTimeRange,
+}
+
+impl ProfileCountsQuery {
+    pub fn from_json(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut query: Self = serde_json::from_slice(bytes).context("invalid counts query")?;
+        anyhow::ensure!(
+            query.cookies.len() <= MAX_COUNTS_COOKIES,
+            "more than {} cookies",
+            MAX_COUNTS_COOKIES
+        );
+        for cookie in &mut query.cookies {
+            *cookie = user_tag::normalize_cookie(cookie)?.to_string();
+        }
+        Ok(query)
+    }
+}
+
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct ProfileCounts {
+    pub cookie: String,
+    pub views: usize,
+    pub buys: usize,
+}
+
+impl ProfileCounts {
+    // Counts the tags of a cookie within the time range, regardless of the profile limit.
+    pub fn new(cookie: String, tags: &[UserTag], time_range: &SimpleTimeRange) -> Self {
+        let in_range = || tags.iter().filter(|tag| time_range.contains(&tag.time));
+        Self {
+            cookie,
+            views: in_range().filter(|tag| tag.action == Action::View).count(),
+            buys: in_range().filter(|tag| tag.action == Action::Buy).count(),
+        }
+    }
+}
+
+// Serializes with milliseconds. Replies use `with_precision`.
+impl Serialize for UserProfilesReply {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.with_precision(DatetimePrecision::Millis)
+            .serialize(serializer)
+    }
+}
+
+// A reply serialized with datetimes of the given precision.
+pub struct ReplyWithPrecision<'a> {
+    reply: &'a UserProfilesReply,
+    precision: DatetimePrecision,
+}
+
+struct TagsWithPrecision<'a> {
+    tags: &'a [UserTag],
+    precision: DatetimePrecision,
+}
+
+impl Serialize for TagsWithPrecision<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            self.tags
+                .iter()
+                .map(|tag| tag.with_precision(self.precision)),
+        )
+    }
+}
+
+impl Serialize for ReplyWithPrecision<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Fields<'a> {
+            cookie: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            views: Option<TagsWithPrecision<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            buys: Option<TagsWithPrecision<'a>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            last_seen: Option<String>,
+        }
+
+        let (reply, precision) = (self.reply, self.precision);
+        Fields {
+            cookie: &reply.cookie,
+            views: reply
+                .views
+                .as_deref()
+                .map(|tags| TagsWithPrecision { tags, precision }),
+            buys: reply
+                .buys
+                .as_deref()
+                .map(|tags| TagsWithPrecision { tags, precision }),
+            last_seen: reply
+                .last_seen
+                .map(|time| user_tag::format_datetime(&time, precision)),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_utils, user_tag::Action};
+    use chrono::{TimeZone, Timelike};
+
+    fn make_tag(action: Action, minute: u32) -> UserTag {
+        make_product_tag(action, minute, 1)
+    }
+
+    fn make_product_tag(action: Action, minute: u32, product_id: i32) -> UserTag {
+        let mut tag = UserTag {
+            time: Utc.with_ymd_and_hms(2022, 3, 22, 12, minute, 0).unwrap(),
+            action,
+            ..test_utils::sample_tag()
+        };
+        tag.product_info.product_id = product_id;
+        tag
+    }
+
+    #[test]
+    fn last_seen() {
+        let reply = UserProfilesReply::new(
+            "cookie".into(),
+            Some(vec![make_tag(Action::View, 20), make_tag(Action::View, 15)]),
+            Some(vec![make_tag(Action::Buy, 25)]),
+            200,
+        )
+        .with_last_seen();
+        assert_eq!(
+            reply.last_seen,
+            Some(Utc.with_ymd_and_hms(2022, 3, 22, 12, 25, 0).unwrap())
+        );
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert_eq!(serialized["last_seen"], "2022-03-22T12:25:00.000Z");
+
+        let reply = UserProfilesReply::new("cookie".into(), Some(vec![]), Some(vec![]), 200)
+            .with_last_seen();
+        assert_eq!(reply.last_seen, None);
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert!(serialized.get("last_seen").is_none());
+    }
+
+    #[test]
+    fn reply_precision() {
+        let reply = UserProfilesReply::new(
+            "cookie".into(),
+            Some(vec![make_tag(Action::View, 20)]),
+            None,
+            200,
+        )
+        .with_last_seen();
+
+        let serialized =
+            serde_json::to_value(reply.with_precision(DatetimePrecision::Seconds)).unwrap();
+        assert_eq!(serialized["views"][0]["time"], "2022-03-22T12:20:00Z");
+        assert_eq!(serialized["last_seen"], "2022-03-22T12:20:00Z");
+        assert!(serialized.get("buys").is_none());
+
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert_eq!(serialized["views"][0]["time"], "2022-03-22T12:20:00.000Z");
+    }
+
+    #[test]
+    fn spec_reply_keys() {
+        let record = || Some(vec![make_tag(Action::View, 20), make_tag(Action::Buy, 25)]);
+        let mut query: UserProfilesQuery = serde_json::from_value(serde_json::json!({
+            "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+        }))
+        .unwrap();
+
+        let reply = UserProfilesReply::from_record("cookie".into(), record(), &query).unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        let keys = serialized.as_object().unwrap().keys().collect::<Vec<_>>();
+        assert_eq!(keys, ["buys", "cookie", "views"]);
+
+        query.include_last_seen = true;
+        let reply = UserProfilesReply::from_record("cookie".into(), record(), &query).unwrap();
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert_eq!(serialized["last_seen"], "2022-03-22T12:25:00.000Z");
+    }
+
+    #[test]
+    fn deterministic_truncation() {
+        let tags = (0..30)
+            .map(|i| {
+                let mut tag = make_product_tag(Action::View, 15, i % 3);
+                tag.origin = format!("origin-{}", i % 5);
+                tag.product_info.price = i;
+                tag
+            })
+            .collect::<Vec<_>>();
+        let truncate = |tags: Vec<UserTag>| {
+            UserProfilesReply::new("cookie".into(), Some(tags), None, 7)
+                .views
+                .unwrap()
+        };
+
+        let expected = truncate(tags.clone());
+        assert_eq!(expected.len(), 7);
+        let prices = expected
+            .iter()
+            .map(|tag| tag.product_info.price)
+            .collect::<Vec<_>>();
+        assert_eq!(prices, [0, 15, 6, 21, 12, 27, 3]);
+
+        let mut reversed = tags.clone();
+        reversed.reverse();
+        assert_eq!(truncate(reversed), expected);
+        for shift in [1, 7, 13] {
+            let mut rotated = tags.clone();
+            rotated.rotate_left(shift);
+            assert_eq!(truncate(rotated), expected);
+        }
+    }
+
+    #[test]
+    fn stable_order() {
+        let products = |reply: &UserProfilesReply| {
+            reply
+                .views
+                .iter()
+                .flatten()
+                .map(|tag| (tag.time.minute(), tag.product_info.product_id))
+                .collect::<Vec<_>>()
+        };
+
+        let first = UserProfilesReply::new(
+            "cookie".into(),
+            Some(vec![
+                make_product_tag(Action::View, 15, 3),
+                make_product_tag(Action::View, 20, 2),
+                make_product_tag(Action::View, 15, 1),
+                make_product_tag(Action::View, 15, 2),
+            ]),
+            None,
+            3,
+        );
+        let second = UserProfilesReply::new(
+            "cookie".into(),
+            Some(vec![
+                make_product_tag(Action::View, 15, 2),
+                make_product_tag(Action::View, 15, 1),
+                make_product_tag(Action::View, 15, 3),
+                make_product_tag(Action::View, 20, 2),
+            ]),
+            None,
+            3,
+        );
+
+        assert_eq!(products(&first), [(20, 2), (15, 1), (15, 2)]);
+        assert_eq!(products(&first), products(&second));
+    }
+
+    #[test]
+    fn single_action() {
+        let query: UserProfilesQuery = serde_json::from_value(serde_json::json!({
+            "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+            "action": "BUY",
+        }))
+        .unwrap();
+        assert!(query.includes(Action::Buy));
+        assert!(!query.includes(Action::View));
+
+        let reply = UserProfilesReply::new(
+            "cookie".into(),
+            query.includes(Action::View).then(Vec::new),
+            query
+                .includes(Action::Buy)
+                .then(|| vec![make_tag(Action::Buy, 25)]),
+            200,
+        );
+        let serialized = serde_json::to_value(&reply).unwrap();
+        assert!(serialized.get("views").is_none());
+        assert_eq!(serialized["buys"].as_array().unwrap().len(), 1);
+
+        let query: UserProfilesQuery = serde_json::from_value(serde_json::json!({
+            "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+        }))
+        .unwrap();
+        assert!(query.includes(Action::Buy));
+        assert!(query.includes(Action::View));
+    }
+
+    #[test]
+    fn query_from_request() {
+        let query_string = "time_range=2022-03-22T12:15:00.000_2022-03-22T12:30:00.000&limit=10";
+
+        // The spec's debug mode sends the expected reply in the body.
+        let query = UserProfilesQuery::from_request(
+            query_string,
+            br#"{"cookie": "c", "views": [], "buys": []}"#,
+        )
+        .unwrap();
+        assert_eq!(query.limit, 10);
+
+        // A query in the body must agree with the query string.
+        let body =
+            br#"{"time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000", "limit": 10}"#;
+        let query = UserProfilesQuery::from_request(query_string, body).unwrap();
+        assert_eq!(query.limit, 10);
+        let body =
+            br#"{"time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000", "limit": 5}"#;
+        let e = UserProfilesQuery::from_request(query_string, body).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the query in the body differs from the query string"
+        );
+        let e = UserProfilesQuery::from_request(query_string, br#"{"limit": 5}"#).unwrap_err();
+        assert_eq!(e.to_string(), "invalid query in the body");
+
+        let query = UserProfilesQuery::from_request(
+            "pretty=true",
+            br#"{"time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000", "limit": 5}"#,
+        )
+        .unwrap();
+        assert_eq!(query.limit, 5);
+
+        // Neither a query string nor a query in the body.
+        UserProfilesQuery::from_request("", br#"{"cookie": "c", "views": [], "buys": []}"#)
+            .unwrap_err();
+        UserProfilesQuery::from_request("", b"").unwrap_err();
+    }
+
+    #[test]
+    fn unknown_cookie() {
+        let query: UserProfilesQuery = serde_json::from_value(serde_json::json!({
+            "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+        }))
+        .unwrap();
+        assert!(!query.not_found_if_unknown);
+        let reply = UserProfilesReply::from_record("cookie".into(), None, &query).unwrap();
+        assert!(reply.views.unwrap().is_empty());
+        assert!(reply.buys.unwrap().is_empty());
+
+        let query: UserProfilesQuery = serde_json::from_value(serde_json::json!({
+            "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+            "not_found_if_unknown": true,
+        }))
+        .unwrap();
+        assert!(UserProfilesReply::from_record("cookie".into(), None, &query).is_none());
+
+        // Known, but nothing within the range.
+        let record = vec![make_tag(Action::View, 10), make_tag(Action::Buy, 30)];
+        let reply = UserProfilesReply::from_record("cookie".into(), Some(record), &query).unwrap();
+        assert_eq!(reply.last_seen, None);
+        assert!(reply.views.unwrap().is_empty());
+        assert!(reply.buys.unwrap().is_empty());
+    }
+
+    #[test]
+    fn aggregate_profile() {
+        let mut other_product = make_product_tag(Action::Buy, 16, 2);
+        other_product.product_info.price = 50;
+        let reply = UserProfilesReply::new(
+            "cookie".into(),
+            Some(vec![make_tag(Action::View, 15), make_tag(Action::View, 16)]),
+            Some(vec![
+                make_tag(Action::Buy, 16),
+                other_product,
+                make_tag(Action::Buy, 16),
+                make_tag(Action::Buy, 18),
+            ]),
+            200,
+        );
+        let make_query = |pairs: &[(&str, &str)]| {
+            let mut pairs = test_utils::make_pairs(pairs);
+            pairs.push((
+                "time_range".into(),
+                "2022-03-22T12:15:00_2022-03-22T12:18:00".into(),
+            ));
+            AggregatesQuery::from_pairs(pairs, crate::time_range::MAX_RANGE_BUCKETS).unwrap()
+        };
+        let bucket = |minute| Utc.with_ymd_and_hms(2022, 3, 22, 12, minute, 0).unwrap();
+
+        let query = make_query(&[
+            ("action", "BUY"),
+            ("aggregates", "COUNT"),
+            ("aggregates", "SUM_PRICE"),
+            ("aggregates", "DISTINCT_PRODUCTS"),
+        ]);
+        let rows = reply.aggregate(&query);
+        let expected = HashMap::from([(
+            bucket(16),
+            AggregatesRow {
+                count: Some(3),
+                sum_price: Some(250),
+                distinct_products: Some(2),
+            },
+        )]);
+        assert_eq!(rows, expected);
+
+        let query = make_query(&[
+            ("action", "VIEW"),
+            ("brand_id", "brand"),
+            ("aggregates", "COUNT"),
+        ]);
+        let rows = reply.aggregate(&query);
+        let expected = [15, 16].map(|minute| {
+            let row = AggregatesRow {
+                count: Some(1),
+                sum_price: None,
+                distinct_products: None,
+            };
+            (bucket(minute), row)
+        });
+        assert_eq!(rows, HashMap::from(expected));
+
+        let query = make_query(&[
+            ("action", "BUY"),
+            ("origin", "elsewhere"),
+            ("aggregates", "COUNT"),
+        ]);
+        assert!(reply.aggregate(&query).is_empty());
+    }
+
+    #[test]
+    fn counts() {
+        let query = ProfileCountsQuery::from_json(
+            br#"{"cookies": ["a", " b ", "c"], "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000"}"#,
+        )
+        .unwrap();
+        assert_eq!(query.cookies, ["a", "b", "c"]);
+
+        let tags_of = |cookie: &str| {
+            let minutes: &[(Action, u32)] = match cookie {
+                "a" => &[(Action::View, 10), (Action::View, 15), (Action::Buy, 29)],
+                "b" => &[(Action::Buy, 15), (Action::Buy, 30), (Action::View, 20)],
+                _ => &[],
+            };
+            minutes
+                .iter()
+                .map(|(action, minute)| make_tag(*action, *minute))
+                .collect::<Vec<_>>()
+        };
+
+        for cookie in &query.cookies {
+            let tags = tags_of(cookie);
+            let counts = ProfileCounts::new(cookie.clone(), &tags, &query.time_range);
+
+            let (views, buys): (Vec<_>, Vec<_>) = tags
+                .into_iter()
+                .filter(|tag| query.time_range.contains(&tag.time))
+                .partition(|tag| tag.action == Action::View);
+            let full = UserProfilesReply::new(cookie.clone(), Some(views), Some(buys), 200);
+            assert_eq!(counts.views, full.views.unwrap().len());
+            assert_eq!(counts.buys, full.buys.unwrap().len());
+        }
+
+        let a = ProfileCounts::new("a".into(), &tags_of("a"), &query.time_range);
+        assert_eq!((a.views, a.buys), (1, 1));
+
+        let cookies = vec!["cookie"; MAX_COUNTS_COOKIES + 1];
+        let body = serde_json::json!({
+            "cookies": cookies,
+            "time_range": "2022-03-22T12:15:00.000_2022-03-22T12:30:00.000",
+        });
+        ProfileCountsQuery::from_json(body.to_string().as_bytes()).unwrap_err();
+    }
 }
@@ -2,7 +2,7 @@ use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize, Serializer};
 use std::fmt::{self, Display, Formatter};
 
-#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum Device {
     Pc,
@@ -10,7 +10,7 @@ pub enum Device {
     Tv,
 }
 
-#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum Action {
     View,
@@ -26,7 +26,7 @@ impl Display for Action {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Hash, Clone, Debug)]
 pub struct ProductInfo {
     pub product_id: i32,
     pub brand_id: String,
@@ -34,9 +34,19 @@ pub struct ProductInfo {
     pub price: i32,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// The version of payloads without `schema_version`, i.e. the tags from the spec. It stays 1 when
+/// `SCHEMA_VERSION` is bumped.
+pub const LEGACY_SCHEMA_VERSION: u8 = 1;
+
+/// Payloads without `schema_version` are `LEGACY_SCHEMA_VERSION`, which is also left out when
+/// serializing, so legacy tags look exactly like the ones from the spec.
+///
+/// Migration contract: unknown fields are ignored, so older readers accept newer payloads. Fields
+/// added in later versions must have a serde default, so newer readers accept older payloads.
+#[derive(Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
 pub struct UserTag {
-    #[serde(serialize_with = "serialize_datetime")]
     pub time: DateTime<Utc>,
     pub cookie: String,
     pub country: String,
@@ -44,34 +54,259 @@ pub struct UserTag {
     pub action: Action,
     pub origin: String,
     pub product_info: ProductInfo,
+    #[serde(default = "UserTag::legacy_schema_version")]
+    pub schema_version: u8,
+}
+
+impl UserTag {
+    fn legacy_schema_version() -> u8 {
+        LEGACY_SCHEMA_VERSION
+    }
+
+    fn is_legacy_schema_version(version: &u8) -> bool {
+        *version == LEGACY_SCHEMA_VERSION
+    }
+
+    // Errors name the offending field, e.g. `product_info.price: invalid type`.
+    pub fn from_json(bytes: &[u8]) -> anyhow::Result<Self> {
+        let deserializer = &mut serde_json::Deserializer::from_slice(bytes);
+        let tag = serde_path_to_error::deserialize(deserializer)?;
+        Ok(tag)
+    }
+
+    // Keeps all tags of a user in one partition, so they are consumed in order.
+    pub fn partition_key(&self) -> String {
+        self.cookie.clone()
+    }
+
+    pub fn with_precision(&self, precision: DatetimePrecision) -> TagWithPrecision<'_> {
+        TagWithPrecision {
+            tag: self,
+            precision,
+        }
+    }
+}
+
+// Serializes with milliseconds, e.g. Kafka payloads. Replies use `with_precision`.
+impl Serialize for UserTag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.with_precision(DatetimePrecision::Millis)
+            .serialize(serializer)
+    }
+}
+
+// A tag serialized with datetimes of the given precision.
+pub struct TagWithPrecision<'a> {
+    tag: &'a UserTag,
+    precision: DatetimePrecision,
+}
+
+impl Serialize for TagWithPrecision<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Fields<'a> {
+            time: String,
+            cookie: &'a str,
+            country: &'a str,
+            device: Device,
+            action: Action,
+            origin: &'a str,
+            product_info: &'a ProductInfo,
+            #[serde(skip_serializing_if = "UserTag::is_legacy_schema_version")]
+            schema_version: u8,
+        }
+
+        let tag = self.tag;
+        Fields {
+            time: format_datetime(&tag.time, self.precision),
+            cookie: &tag.cookie,
+            country: &tag.country,
+            device: tag.device,
+            action: tag.action,
+            origin: &tag.origin,
+            product_info: &tag.product_info,
+            schema_version: tag.schema_version,
+        }
+        .serialize(serializer)
+    }
+}
+
+pub const MAX_COOKIE_LEN: usize = 256;
+
+// Cookies are used as storage keys, so both ingestion and reads go through this.
+pub fn normalize_cookie(cookie: &str) -> anyhow::Result<&str> {
+    let cookie = cookie.trim();
+    anyhow::ensure!(!cookie.is_empty(), "empty cookie");
+    anyhow::ensure!(cookie.len() <= MAX_COOKIE_LEN, "cookie too long");
+    anyhow::ensure!(
+        !cookie.chars().any(char::is_control),
+        "cookie contains control characters"
+    );
+    Ok(cookie)
+}
+
+// Fractional digits of datetimes in replies. Any precision is accepted when deserializing.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DatetimePrecision {
+    Seconds,
+    #[default]
+    Millis,
+    Nanos,
+}
+
+impl DatetimePrecision {
+    fn seconds_format(self) -> SecondsFormat {
+        match self {
+            Self::Seconds => SecondsFormat::Secs,
+            Self::Millis => SecondsFormat::Millis,
+            Self::Nanos => SecondsFormat::Nanos,
+        }
+    }
 }
 
-fn serialize_datetime<S: Serializer>(
-    datetime: &DateTime<Utc>,
-    serializer: S,
-) -> Result<S::Ok, S::Error> {
-    let as_string = datetime.to_rfc3339_opts(SecondsFormat::Millis, true);
-    serializer.serialize_str(&as_string)
+pub(crate) fn format_datetime(datetime: &DateTime<Utc>, precision: DatetimePrecision) -> String {
+    datetime.to_rfc3339_opts(precision.seconds_format(), true)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_utils::SAMPLE_TAG;
     use chrono::TimeZone;
-    use serde_json::Serializer;
 
     #[test]
     fn ser_de_datetime() {
-        let as_str = "\"2022-03-22T12:15:00.000Z\"";
+        let as_str = "2022-03-22T12:15:00.000Z";
         let expected = Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap();
 
-        let deserialized: DateTime<Utc> = serde_json::from_str(as_str).unwrap();
+        let deserialized: DateTime<Utc> = serde_json::from_value(as_str.into()).unwrap();
         assert_eq!(deserialized, expected);
 
-        let mut buffer = vec![];
-        let mut serializer = Serializer::new(&mut buffer);
-        serialize_datetime(&expected, &mut serializer).unwrap();
-        let serialized = String::from_utf8(buffer).unwrap();
+        let serialized = format_datetime(&expected, DatetimePrecision::Millis);
         assert_eq!(serialized, as_str);
     }
+
+    #[test]
+    fn datetime_precisions() {
+        let whole = Utc.with_ymd_and_hms(2022, 3, 22, 12, 15, 0).unwrap();
+        let fractional = whole + chrono::Duration::nanoseconds(123_456_789);
+
+        let cases = [
+            (DatetimePrecision::Seconds, "2022-03-22T12:15:00Z", whole),
+            (
+                DatetimePrecision::Millis,
+                "2022-03-22T12:15:00.123Z",
+                whole + chrono::Duration::milliseconds(123),
+            ),
+            (
+                DatetimePrecision::Nanos,
+                "2022-03-22T12:15:00.123456789Z",
+                fractional,
+            ),
+        ];
+        for (precision, expected, truncated) in cases {
+            let formatted = format_datetime(&fractional, precision);
+            assert_eq!(formatted, expected);
+            let parsed: DateTime<Utc> = formatted.parse().unwrap();
+            assert_eq!(parsed, truncated);
+
+            let parsed: DateTime<Utc> = format_datetime(&whole, precision).parse().unwrap();
+            assert_eq!(parsed, whole);
+        }
+    }
+
+    #[test]
+    fn reply_precision() {
+        let mut tag = crate::test_utils::sample_tag();
+        tag.time += chrono::Duration::nanoseconds(123_456_789);
+
+        let reply = serde_json::to_value(tag.with_precision(DatetimePrecision::Seconds)).unwrap();
+        assert_eq!(reply["time"], "2022-03-22T12:15:00Z");
+        assert_eq!(
+            serde_json::to_value(tag.with_precision(DatetimePrecision::Millis)).unwrap(),
+            serde_json::to_value(&tag).unwrap()
+        );
+
+        // Kafka payloads keep milliseconds whatever the precision of replies.
+        let payload = event_queue::codec::Codec::Json.encode(&tag);
+        let payload: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload["time"], "2022-03-22T12:15:00.123Z");
+    }
+
+    #[test]
+    fn schema_versions() {
+        let v1 = SAMPLE_TAG;
+        let tag: UserTag = serde_json::from_str(v1).unwrap();
+        assert_eq!(tag.schema_version, LEGACY_SCHEMA_VERSION);
+        assert_eq!(serde_json::to_string(&tag).unwrap(), v1);
+
+        // A payload from a newer producer, with a field this version does not know about.
+        let v2 = r#"{"time":"2022-03-22T12:15:00.000Z","cookie":"cookie","country":"PL","device":"PC","action":"VIEW","origin":"origin","product_info":{"product_id":1,"brand_id":"brand","category_id":"category","price":100},"schema_version":2,"referrer":"https://example.com"}"#;
+        let tag: UserTag = serde_json::from_str(v2).unwrap();
+        assert_eq!(tag.schema_version, 2);
+        assert_eq!(tag.cookie, "cookie");
+        let serialized = serde_json::to_value(&tag).unwrap();
+        assert_eq!(serialized["schema_version"], 2);
+        assert!(serialized.get("referrer").is_none());
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        use event_queue::codec::Codec;
+
+        let as_str = SAMPLE_TAG;
+        let tag: UserTag = serde_json::from_str(as_str).unwrap();
+
+        let payload = Codec::MsgPack.encode(&tag);
+        let decoded: UserTag = Codec::MsgPack.decode(&payload).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&tag).unwrap()
+        );
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_cookie("cookie").unwrap(), "cookie");
+        assert_eq!(normalize_cookie(" cookie\n").unwrap(), "cookie");
+        normalize_cookie("").unwrap_err();
+        normalize_cookie("   ").unwrap_err();
+        normalize_cookie("coo\u{0}kie").unwrap_err();
+        normalize_cookie(&"a".repeat(MAX_COOKIE_LEN)).unwrap();
+        normalize_cookie(&"a".repeat(MAX_COOKIE_LEN + 1)).unwrap_err();
+    }
+
+    #[test]
+    fn error_path() {
+        let missing_price = r#"{"time":"2022-03-22T12:15:00.000Z","cookie":"cookie","country":"PL","device":"PC","action":"VIEW","origin":"origin","product_info":{"product_id":1,"brand_id":"brand","category_id":"category"}}"#;
+        let e = UserTag::from_json(missing_price.as_bytes()).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("product_info: missing field `price`"),
+            "{}",
+            e
+        );
+
+        let wrong_action = r#"{"time":"2022-03-22T12:15:00.000Z","cookie":"cookie","country":"PL","device":"PC","action":"SELL","origin":"origin","product_info":{"product_id":1,"brand_id":"brand","category_id":"category","price":"100"}}"#;
+        let e = UserTag::from_json(wrong_action.as_bytes()).unwrap_err();
+        assert!(
+            e.to_string().starts_with("action: unknown variant `SELL`"),
+            "{}",
+            e
+        );
+
+        let wrong_price = wrong_action.replace(r#""action":"SELL""#, r#""action":"BUY""#);
+        let e = UserTag::from_json(wrong_price.as_bytes()).unwrap_err();
+        assert!(
+            e.to_string()
+                .starts_with("product_info.price: invalid type"),
+            "{}",
+            e
+        );
+
+        let valid = wrong_price.replace(r#""price":"100""#, r#""price":100"#);
+        let tag = UserTag::from_json(valid.as_bytes()).unwrap();
+        assert_eq!(tag.product_info.price, 100);
+    }
 }
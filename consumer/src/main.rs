@@ -1,41 +1,306 @@
 use anyhow::Context;
-use api_server::user_tag::UserTag;
+use api_server::{shutdown::shutdown_signal, user_tag::UserTag};
 use async_trait::async_trait;
-use event_queue::consumer::{EventProcessor, EventStream};
+use chrono::{DateTime, Duration, Utc};
+use event_queue::{
+    codec::Codec,
+    consumer::{EventProcessor, EventStream, OffsetReset, ReplayRange, StreamConfig},
+    retry::{self, RetryConfig},
+};
 use serde::Deserialize;
-use std::{net::SocketAddr, process::ExitCode};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time,
+};
 use tokio::{
-    signal,
     sync::oneshot::{self, Receiver},
+    task,
 };
 
-struct DummyProcessor;
+// The most recently consumed tags, to skip exact repeats e.g. after producer retries.
+struct RecentTags {
+    capacity: usize,
+    order: VecDeque<UserTag>,
+    seen: HashSet<UserTag>,
+}
+
+impl RecentTags {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    // Returns false if an identical tag is still remembered.
+    fn insert(&mut self, tag: &UserTag) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if !self.seen.insert(tag.clone()) {
+            return false;
+        }
+
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(tag.clone());
+        true
+    }
+}
+
+struct DummyProcessor {
+    max_future_skew: Duration,
+    recent: Mutex<RecentTags>,
+}
+
+impl DummyProcessor {
+    fn accepts(&self, tag: &UserTag, now: DateTime<Utc>) -> bool {
+        tag.time <= now + self.max_future_skew
+    }
+
+    fn is_duplicate(&self, tag: &UserTag) -> bool {
+        !self.recent.lock().unwrap().insert(tag)
+    }
+}
 
 #[async_trait]
 impl EventProcessor for DummyProcessor {
     type Event = UserTag;
 
     async fn process(&self, event: Self::Event) -> anyhow::Result<()> {
+        // Skipped tags still have their offsets stored, they would be rejected again anyway.
+        if !self.accepts(&event, Utc::now()) {
+            log::warn!("Skipping tag from the future {:?}", event);
+            return Ok(());
+        }
+        if self.is_duplicate(&event) {
+            log::debug!("Skipping duplicate tag {:?}", event);
+            return Ok(());
+        }
+
         log::info!("Consuming tag {:?}", event);
         Ok(())
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 struct Args {
     kafka_brokers: Vec<SocketAddr>,
     kafka_group: String,
     kafka_topic: String,
+    #[serde(default)]
+    kafka_config: Vec<String>,
+    kafka_fetch_min_bytes: Option<u32>,
+    kafka_fetch_wait_max_ms: Option<u32>,
+    kafka_group_instance_id: Option<String>,
+    kafka_store_batch_size: Option<usize>,
+    #[serde(default)]
+    kafka_codec: Codec,
+    #[serde(default)]
+    kafka_offset_reset: OffsetReset,
+    #[serde(default = "default_max_future_skew_secs")]
+    max_future_skew_secs: i64,
+    #[serde(default = "default_lag_interval_secs")]
+    kafka_lag_interval_secs: u64,
+    #[serde(default)]
+    dedup_window: usize,
+    #[serde(default)]
+    kafka_connect_attempts: u32,
+    #[serde(default = "default_kafka_connect_interval_ms")]
+    kafka_connect_interval_ms: u64,
+}
+
+impl Args {
+    fn load() -> anyhow::Result<Self> {
+        Self::from_vars(std::env::vars())
+    }
+
+    fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> anyhow::Result<Self> {
+        let args: Self =
+            envy::from_iter(vars).context("failed to parse config from environment variables")?;
+        args.validate()?;
+        Ok(args)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.kafka_brokers.is_empty(),
+            "kafka_brokers must not be empty"
+        );
+        anyhow::ensure!(
+            !self.kafka_group.is_empty(),
+            "kafka_group must not be empty"
+        );
+        anyhow::ensure!(
+            !self.kafka_topic.is_empty(),
+            "kafka_topic must not be empty"
+        );
+        anyhow::ensure!(
+            self.kafka_lag_interval_secs > 0,
+            "kafka_lag_interval_secs must be positive"
+        );
+        anyhow::ensure!(
+            self.kafka_store_batch_size != Some(0),
+            "kafka_store_batch_size must be positive"
+        );
+        anyhow::ensure!(
+            self.max_future_skew_secs >= 0,
+            "max_future_skew_secs must not be negative"
+        );
+        anyhow::ensure!(
+            self.kafka_connect_attempts == 0 || self.kafka_connect_interval_ms > 0,
+            "kafka_connect_interval_ms must be positive"
+        );
+        Ok(())
+    }
+}
+
+fn default_max_future_skew_secs() -> i64 {
+    300
+}
+
+#[derive(Deserialize)]
+struct ReplayArgs {
+    replay_partition: i32,
+    replay_start_offset: i64,
+    replay_stop_offset: Option<i64>,
+}
+
+impl ReplayArgs {
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.replay_start_offset >= 0,
+            "replay_start_offset must not be negative"
+        );
+        if let Some(stop) = self.replay_stop_offset {
+            anyhow::ensure!(
+                stop > self.replay_start_offset,
+                "replay_stop_offset must be greater than replay_start_offset"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_lag_interval_secs() -> u64 {
+    60
+}
+
+fn default_kafka_connect_interval_ms() -> u64 {
+    1000
+}
+
+fn wait_for_brokers(stream: &EventStream, config: &RetryConfig) -> anyhow::Result<()> {
+    if config.attempts > 0 {
+        log::info!("Waiting for the Kafka brokers");
+        task::block_in_place(|| retry::retry(config, || stream.check_connection(config.interval)))?;
+    }
+    Ok(())
+}
+
+// Lag queries block for up to a broker round trip per partition, so they run on the blocking pool
+// instead of stalling consumption.
+async fn report_lag(stream: Arc<EventStream>, interval: time::Duration) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let stream = stream.clone();
+        let lags = task::spawn_blocking(move || stream.lag())
+            .await
+            .context("lag query panicked")
+            .and_then(|res| res);
+        match lags {
+            Ok(lags) => {
+                for lag in lags {
+                    log::info!(
+                        "Consumer lag of {}/{}: {}",
+                        lag.topic,
+                        lag.partition,
+                        lag.lag
+                    );
+                }
+            }
+            Err(e) => log::warn!("Failed to compute consumer lag: {:?}", e),
+        }
+    }
+}
+
+fn parse_kafka_config(entries: &[String]) -> anyhow::Result<HashMap<String, String>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let (key, value) = entry
+                .split_once('=')
+                .with_context(|| format!("invalid Kafka config entry {}", entry))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
 }
 
 async fn run_consumer(stop: Receiver<()>) -> anyhow::Result<()> {
-    let args: Args =
-        envy::from_env().context("failed to parse config from environment variables")?;
-    let stream = EventStream::new(&args.kafka_brokers, args.kafka_group, args.kafka_topic)?;
+    let args = Args::load()?;
+    let defaults = StreamConfig::default();
+    let config = StreamConfig {
+        fetch_min_bytes: args
+            .kafka_fetch_min_bytes
+            .unwrap_or(defaults.fetch_min_bytes),
+        fetch_wait_max_ms: args
+            .kafka_fetch_wait_max_ms
+            .unwrap_or(defaults.fetch_wait_max_ms),
+        group_instance_id: args.kafka_group_instance_id,
+        overrides: parse_kafka_config(&args.kafka_config)?,
+        codec: args.kafka_codec,
+        store_batch_size: args
+            .kafka_store_batch_size
+            .unwrap_or(defaults.store_batch_size),
+        offset_reset: args.kafka_offset_reset,
+    };
+    let retry_config = RetryConfig {
+        attempts: args.kafka_connect_attempts,
+        interval: time::Duration::from_millis(args.kafka_connect_interval_ms),
+    };
+    let processor = DummyProcessor {
+        max_future_skew: Duration::seconds(args.max_future_skew_secs),
+        recent: Mutex::new(RecentTags::new(args.dedup_window)),
+    };
+
+    if std::env::args().nth(1).as_deref() == Some("replay") {
+        let replay_args: ReplayArgs =
+            envy::from_env().context("failed to parse replay config from environment variables")?;
+        replay_args.validate()?;
+        let range = ReplayRange {
+            partition: replay_args.replay_partition,
+            start_offset: replay_args.replay_start_offset,
+            stop_offset: replay_args.replay_stop_offset,
+        };
+        let stream = EventStream::replay(&args.kafka_brokers, args.kafka_topic, &range, &config)?;
+        wait_for_brokers(&stream, &retry_config)?;
+        log::info!("Replaying {:?}", range);
+
+        return tokio::select! {
+            res = stream.consume_range(&processor, &range) => res,
+            _ = stop => Ok(()),
+        };
+    }
+
+    let stream = Arc::new(EventStream::new(
+        &args.kafka_brokers,
+        args.kafka_group,
+        args.kafka_topic,
+        &config,
+    )?);
+    wait_for_brokers(&stream, &retry_config)?;
 
     tokio::select! {
-        res = stream.consume(&DummyProcessor {}) => res,
-        _ = stop => Ok (()),
+        res = stream.consume_until(&processor, stop) => res,
+        res = report_lag(stream.clone(), time::Duration::from_secs(args.kafka_lag_interval_secs)) => res,
     }
 }
 
@@ -46,10 +311,8 @@ async fn main() -> ExitCode {
     let (tx, rx) = oneshot::channel();
     let res = tokio::try_join!(
         async move {
-            signal::ctrl_c()
-                .await
-                .context("failed to listen for ctrl-c")?;
-            log::info!("Received a ctrl-c signal");
+            let name = shutdown_signal()?.await?;
+            log::info!("Received {}, shutting down", name);
             tx.send(()).ok();
             Ok(())
         },
@@ -64,3 +327,95 @@ async fn main() -> ExitCode {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api_server::test_utils;
+
+    fn make_tag(time: DateTime<Utc>) -> UserTag {
+        UserTag {
+            time,
+            ..test_utils::sample_tag()
+        }
+    }
+
+    #[test]
+    fn skip_future_tags() {
+        let processor = DummyProcessor {
+            max_future_skew: Duration::minutes(5),
+            recent: Mutex::new(RecentTags::new(0)),
+        };
+        let now = Utc::now();
+
+        assert!(processor.accepts(&make_tag(now - Duration::hours(1)), now));
+        assert!(processor.accepts(&make_tag(now + Duration::minutes(5)), now));
+        assert!(!processor.accepts(&make_tag(now + Duration::days(365)), now));
+    }
+
+    #[test]
+    fn skip_duplicates() {
+        let processor = DummyProcessor {
+            max_future_skew: Duration::minutes(5),
+            recent: Mutex::new(RecentTags::new(2)),
+        };
+        let now = Utc::now();
+        let tag = make_tag(now);
+
+        assert!(!processor.is_duplicate(&tag));
+        assert!(processor.is_duplicate(&tag.clone()));
+
+        let mut other = make_tag(now);
+        other.product_info.price += 1;
+        assert!(!processor.is_duplicate(&other));
+        assert!(!processor.is_duplicate(&make_tag(now - Duration::minutes(1))));
+
+        // Forgotten once pushed out of the window.
+        assert!(!processor.is_duplicate(&tag));
+
+        let processor = DummyProcessor {
+            max_future_skew: Duration::minutes(5),
+            recent: Mutex::new(RecentTags::new(0)),
+        };
+        assert!(!processor.is_duplicate(&tag));
+        assert!(!processor.is_duplicate(&tag));
+    }
+
+    fn make_vars(overrides: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut vars = HashMap::from([
+            ("kafka_brokers", "127.0.0.1:9092"),
+            ("kafka_group", "group"),
+            ("kafka_topic", "user_tags"),
+        ]);
+        vars.extend(overrides.iter().copied());
+        vars.into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_config() {
+        let mut args = Args::from_vars(make_vars(&[])).unwrap();
+        assert_eq!(args.kafka_lag_interval_secs, 60);
+
+        args.kafka_brokers.clear();
+        assert!(args.validate().is_err());
+
+        Args::from_vars(make_vars(&[("kafka_lag_interval_secs", "0")])).unwrap_err();
+        Args::from_vars(make_vars(&[("max_future_skew_secs", "-1")])).unwrap_err();
+        Args::from_vars(make_vars(&[("kafka_store_batch_size", "0")])).unwrap_err();
+        Args::from_vars(make_vars(&[("kafka_topic", "")])).unwrap_err();
+
+        assert_eq!(args.kafka_offset_reset, OffsetReset::Earliest);
+        let args = Args::from_vars(make_vars(&[("kafka_offset_reset", "latest")])).unwrap();
+        assert_eq!(args.kafka_offset_reset, OffsetReset::Latest);
+        Args::from_vars(make_vars(&[("kafka_offset_reset", "none")])).unwrap_err();
+
+        let replay = ReplayArgs {
+            replay_partition: 0,
+            replay_start_offset: 10,
+            replay_stop_offset: Some(10),
+        };
+        assert!(replay.validate().is_err());
+    }
+}
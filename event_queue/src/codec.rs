@@ -0,0 +1,52 @@
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Format of Kafka payloads, producers and consumers of a topic must use the same one.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Json,
+    MsgPack,
+}
+
+impl Codec {
+    pub fn encode<E: Serialize>(self, event: &E) -> Vec<u8> {
+        match self {
+            Self::Json => serde_json::to_vec(event).expect("serialization to memory buffer failed"),
+            // Named fields keep payloads readable by consumers with a different field order.
+            Self::MsgPack => {
+                rmp_serde::to_vec_named(event).expect("serialization to memory buffer failed")
+            }
+        }
+    }
+
+    pub fn decode<E: DeserializeOwned>(self, payload: &[u8]) -> anyhow::Result<E> {
+        match self {
+            Self::Json => serde_json::from_slice(payload).context("invalid JSON payload"),
+            Self::MsgPack => rmp_serde::from_slice(payload).context("invalid MessagePack payload"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn round_trip() {
+        let event = BTreeMap::from([("a".to_string(), 1), ("b".to_string(), 2)]);
+
+        for codec in [Codec::Json, Codec::MsgPack] {
+            let payload = codec.encode(&event);
+            let decoded: BTreeMap<String, i32> = codec.decode(&payload).unwrap();
+            assert_eq!(decoded, event);
+        }
+
+        let payload = Codec::MsgPack.encode(&event);
+        Codec::Json
+            .decode::<BTreeMap<String, i32>>(&payload)
+            .unwrap_err();
+    }
+}
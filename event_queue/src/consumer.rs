@@ -1,28 +1,212 @@
+use crate::{
+    codec::Codec,
+    lag::{self, PartitionLag},
+};
 use anyhow::Context;
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use futures_util::{
+    future::{self, Either},
+    pin_mut, Future, TryStreamExt,
+};
 use rdkafka::{
     config::ClientConfig,
-    consumer::{Consumer, StreamConsumer},
-    Message,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    error::{KafkaError, KafkaResult},
+    types::RDKafkaErrorCode,
+    Message, Offset, TopicPartitionList,
 };
-use serde::de::DeserializeOwned;
-use std::net::SocketAddr;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
 
 #[async_trait]
 pub trait EventProcessor {
     type Event: DeserializeOwned;
 
+    /// The offset of the event is stored only after this method returns `Ok`, so an event is
+    /// processed at least once. Any write that must survive a crash has to be durable before
+    /// returning.
     async fn process(&self, event: Self::Event) -> anyhow::Result<()>;
 }
 
+/// Where a consumer group without committed offsets starts reading.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum OffsetReset {
+    #[default]
+    Earliest,
+    Latest,
+}
+
+impl OffsetReset {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Earliest => "earliest",
+            Self::Latest => "latest",
+        }
+    }
+
+    // The policy in effect, `overrides` may replace the configured one.
+    fn from_client_config(config: &ClientConfig) -> Self {
+        match config.get("auto.offset.reset") {
+            Some("latest" | "largest" | "end") => Self::Latest,
+            _ => Self::Earliest,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamConfig {
+    pub fetch_min_bytes: u32,
+    pub fetch_wait_max_ms: u32,
+    pub group_instance_id: Option<String>,
+    pub overrides: HashMap<String, String>,
+    pub codec: Codec,
+    /// Offsets are stored once per this many processed events. After a crash or a rebalance, up
+    /// to this many events minus one are delivered again.
+    pub store_batch_size: usize,
+    pub offset_reset: OffsetReset,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            fetch_min_bytes: 1,
+            fetch_wait_max_ms: 500,
+            group_instance_id: None,
+            overrides: Default::default(),
+            codec: Default::default(),
+            store_batch_size: 1,
+            offset_reset: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayRange {
+    pub partition: i32,
+    pub start_offset: i64,
+    /// Exclusive, `None` replays until stopped.
+    pub stop_offset: Option<i64>,
+}
+
+impl ReplayRange {
+    fn assignment(&self, topic: &str) -> KafkaResult<TopicPartitionList> {
+        let mut assignment = TopicPartitionList::new();
+        assignment.add_partition_offset(
+            topic,
+            self.partition,
+            Offset::Offset(self.start_offset),
+        )?;
+        Ok(assignment)
+    }
+
+    fn contains(&self, offset: i64) -> bool {
+        offset >= self.start_offset && !matches!(self.stop_offset, Some(stop) if offset >= stop)
+    }
+}
+
+/// Offsets of processed events waiting to be stored, the latest one of each partition.
+struct PendingOffsets {
+    batch_size: usize,
+    processed: usize,
+    offsets: HashMap<(String, i32), i64>,
+}
+
+impl PendingOffsets {
+    fn new(batch_size: usize) -> Self {
+        Self {
+            batch_size,
+            processed: 0,
+            offsets: Default::default(),
+        }
+    }
+
+    /// Returns the offsets to store once a whole batch of events is processed.
+    fn push(&mut self, topic: &str, partition: i32, offset: i64) -> Vec<((String, i32), i64)> {
+        self.offsets.insert((topic.to_string(), partition), offset);
+        self.processed += 1;
+        if self.processed < self.batch_size {
+            return vec![];
+        }
+        self.take()
+    }
+
+    fn take(&mut self) -> Vec<((String, i32), i64)> {
+        self.processed = 0;
+        self.offsets.drain().collect()
+    }
+}
+
 pub struct EventStream {
     consumer: StreamConsumer,
+    codec: Codec,
+    pending: Mutex<PendingOffsets>,
+    offset_reset: OffsetReset,
 }
 
 impl EventStream {
-    pub fn new(servers: &[SocketAddr], group: String, topic: String) -> anyhow::Result<Self> {
-        let consumer: StreamConsumer = ClientConfig::new()
+    pub fn new(
+        servers: &[SocketAddr],
+        group: String,
+        topic: String,
+        config: &StreamConfig,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            config.store_batch_size > 0,
+            "store_batch_size must be positive"
+        );
+        let client_config = Self::client_config(servers, group, config);
+        let consumer: StreamConsumer = client_config
+            .create()
+            .context("failed to build the Kafka consumer")?;
+
+        consumer
+            .subscribe(&[&topic])
+            .with_context(|| format!("failed to subscribe to the {} topic", topic))?;
+
+        Ok(Self {
+            consumer,
+            codec: config.codec,
+            pending: Mutex::new(PendingOffsets::new(config.store_batch_size)),
+            offset_reset: OffsetReset::from_client_config(&client_config),
+        })
+    }
+
+    /// Reads a single partition from the given offset within a throwaway group, offsets of the
+    /// regular consumer group are left untouched.
+    pub fn replay(
+        servers: &[SocketAddr],
+        topic: String,
+        range: &ReplayRange,
+        config: &StreamConfig,
+    ) -> anyhow::Result<Self> {
+        let group = format!("replay-{}-{}", topic, std::process::id());
+        let consumer: StreamConsumer = Self::client_config(servers, group, config)
+            .set("enable.auto.commit", "false")
+            .create()
+            .context("failed to build the Kafka consumer")?;
+
+        let assignment = range
+            .assignment(&topic)
+            .context("failed to build the replay assignment")?;
+        consumer.assign(&assignment).with_context(|| {
+            format!(
+                "failed to assign partition {} of {}",
+                range.partition, topic
+            )
+        })?;
+
+        Ok(Self {
+            consumer,
+            codec: config.codec,
+            pending: Mutex::new(PendingOffsets::new(1)),
+            offset_reset: config.offset_reset,
+        })
+    }
+
+    fn client_config(servers: &[SocketAddr], group: String, config: &StreamConfig) -> ClientConfig {
+        let mut client_config = ClientConfig::new();
+        client_config
             .set(
                 "bootstrap.servers",
                 servers
@@ -32,38 +216,309 @@ impl EventStream {
                     .join(","),
             )
             .set("group.id", group)
-            .set("auto.offset.reset", "earliest")
+            .set("auto.offset.reset", config.offset_reset.as_str())
             .set("enable.auto.commit", "true")
             .set("enable.auto.offset.store", "false")
-            .create()
-            .context("failed to build the Kafka consumer")?;
+            .set("fetch.min.bytes", config.fetch_min_bytes.to_string())
+            .set("fetch.wait.max.ms", config.fetch_wait_max_ms.to_string());
 
-        consumer
-            .subscribe(&[&topic])
-            .with_context(|| format!("failed to subscribe to the {} topic", topic))?;
+        if let Some(id) = config.group_instance_id.as_ref() {
+            client_config.set("group.instance.id", id);
+        }
+
+        for (key, value) in &config.overrides {
+            client_config.set(key, value);
+        }
 
-        Ok(Self { consumer })
+        client_config
+    }
+
+    /// Fails if no broker answers within `timeout`. Blocks the calling thread.
+    pub fn check_connection(&self, timeout: Duration) -> anyhow::Result<()> {
+        self.consumer
+            .fetch_metadata(None, timeout)
+            .context("failed to reach the Kafka brokers")?;
+        Ok(())
+    }
+
+    /// Blocks until the broker replies.
+    pub fn lag(&self) -> anyhow::Result<Vec<PartitionLag>> {
+        lag::compute_lag(&self.consumer, self.offset_reset)
     }
 
     pub async fn consume<P: EventProcessor>(&self, processor: &P) -> anyhow::Result<()> {
+        self.consume_while(processor, |_| true).await
+    }
+
+    /// Consumes until `stop` completes, then synchronously commits the stored offsets. An event
+    /// being processed at that moment is abandoned and will be delivered again.
+    pub async fn consume_until<P: EventProcessor, S: Future>(
+        &self,
+        processor: &P,
+        stop: S,
+    ) -> anyhow::Result<()> {
+        match until(self.consume(processor), stop).await {
+            Some(res) => res,
+            None => self.commit(),
+        }
+    }
+
+    fn store_offsets(&self, offsets: Vec<((String, i32), i64)>) -> anyhow::Result<()> {
+        for ((topic, partition), offset) in offsets {
+            self.consumer
+                .store_offset(&topic, partition, offset)
+                .context("failed to store offset")?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self) -> anyhow::Result<()> {
+        let pending = self.pending.lock().unwrap().take();
+        self.store_offsets(pending)?;
+
+        match self.consumer.commit_consumer_state(CommitMode::Sync) {
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => Ok(()),
+            res => res.context("failed to commit offsets"),
+        }
+    }
+
+    /// Returns once the stop offset of the range is reached.
+    pub async fn consume_range<P: EventProcessor>(
+        &self,
+        processor: &P,
+        range: &ReplayRange,
+    ) -> anyhow::Result<()> {
+        self.consume_while(processor, |offset| range.contains(offset))
+            .await
+    }
+
+    async fn consume_while<P: EventProcessor, F: Fn(i64) -> bool>(
+        &self,
+        processor: &P,
+        pred: F,
+    ) -> anyhow::Result<()> {
         self.consumer
             .stream()
             .map_err(anyhow::Error::from)
             .map_err(|e| e.context("failed to receive message from Kafka"))
+            .try_take_while(|msg| future::ready(Ok(pred(msg.offset()))))
             .try_for_each(move |msg| async move {
-                let payload = msg.payload().unwrap_or(&[]);
-                let event: P::Event = serde_json::from_slice(payload).with_context(|| {
-                    format!("failed to deserialize message payload {:?}", payload)
-                })?;
-                processor
-                    .process(event)
-                    .await
-                    .context("event consumer failed")?;
-
-                self.consumer
-                    .store_offset_from_message(&msg)
-                    .context("failed to store offset from message")
+                let offsets = process_message(processor, self.codec, &self.pending, &msg).await?;
+                self.store_offsets(offsets)
             })
+            .await?;
+
+        let pending = self.pending.lock().unwrap().take();
+        self.store_offsets(pending)
+    }
+}
+
+/// The offset of the message is recorded only after the processor succeeds. Returns the offsets
+/// to store right away, see `PendingOffsets`.
+async fn process_message<P: EventProcessor, M: Message>(
+    processor: &P,
+    codec: Codec,
+    pending: &Mutex<PendingOffsets>,
+    msg: &M,
+) -> anyhow::Result<Vec<((String, i32), i64)>> {
+    let payload = msg.payload().unwrap_or(&[]);
+    let event: P::Event = codec
+        .decode(payload)
+        .with_context(|| format!("failed to deserialize message payload {:?}", payload))?;
+    processor
+        .process(event)
+        .await
+        .context("event consumer failed")?;
+
+    let offsets = pending
+        .lock()
+        .unwrap()
+        .push(msg.topic(), msg.partition(), msg.offset());
+    Ok(offsets)
+}
+
+// `None` if `stop` completed first.
+async fn until<F: Future, S: Future>(f: F, stop: S) -> Option<F::Output> {
+    pin_mut!(f, stop);
+
+    match future::select(f, stop).await {
+        Either::Left((res, _)) => Some(res),
+        Either::Right(..) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rdkafka::{message::OwnedMessage, Timestamp};
+
+    #[test]
+    fn fetch_config() {
+        let servers = ["127.0.0.1:9092".parse().unwrap()];
+        let config = StreamConfig {
+            fetch_min_bytes: 1024,
+            fetch_wait_max_ms: 100,
+            ..Default::default()
+        };
+
+        let config = EventStream::client_config(&servers, "group".into(), &config);
+        assert_eq!(config.get("fetch.min.bytes"), Some("1024"));
+        assert_eq!(config.get("fetch.wait.max.ms"), Some("100"));
+    }
+
+    #[test]
+    fn group_instance_id() {
+        let servers = ["127.0.0.1:9092".parse().unwrap()];
+        let config = StreamConfig {
+            group_instance_id: Some("consumer-0".into()),
+            ..Default::default()
+        };
+
+        let client_config = EventStream::client_config(&servers, "group".into(), &config);
+        assert_eq!(client_config.get("group.instance.id"), Some("consumer-0"));
+
+        let client_config =
+            EventStream::client_config(&servers, "group".into(), &Default::default());
+        assert_eq!(client_config.get("group.instance.id"), None);
+    }
+
+    #[test]
+    fn store_in_batches() {
+        let mut pending = PendingOffsets::new(3);
+        assert!(pending.push("tags", 0, 10).is_empty());
+        assert!(pending.push("tags", 1, 20).is_empty());
+
+        let mut offsets = pending.push("tags", 0, 11);
+        offsets.sort();
+        assert_eq!(
+            offsets,
+            [(("tags".to_string(), 0), 11), (("tags".to_string(), 1), 20)]
+        );
+
+        assert!(pending.push("tags", 1, 21).is_empty());
+        assert_eq!(pending.take(), [(("tags".to_string(), 1), 21)]);
+        assert!(pending.take().is_empty());
+
+        let mut pending = PendingOffsets::new(1);
+        assert_eq!(pending.push("tags", 0, 10), [(("tags".to_string(), 0), 10)]);
+    }
+
+    #[test]
+    fn replay_range() {
+        let range = ReplayRange {
+            partition: 3,
+            start_offset: 100,
+            stop_offset: Some(200),
+        };
+
+        let assignment = range.assignment("tags").unwrap();
+        let elem = assignment.find_partition("tags", 3).unwrap();
+        assert_eq!(elem.offset(), Offset::Offset(100));
+        assert_eq!(assignment.count(), 1);
+
+        assert!(!range.contains(99));
+        assert!(range.contains(100));
+        assert!(range.contains(199));
+        assert!(!range.contains(200));
+
+        let range = ReplayRange {
+            stop_offset: None,
+            ..range
+        };
+        assert!(range.contains(i64::MAX));
+    }
+
+    #[test]
+    fn config_overrides() {
+        let servers = ["127.0.0.1:9092".parse().unwrap()];
+        let config = StreamConfig {
+            overrides: HashMap::from([
+                ("auto.offset.reset".to_string(), "latest".to_string()),
+                ("fetch.max.bytes".to_string(), "1048576".to_string()),
+            ]),
+            ..Default::default()
+        };
+
+        let config = EventStream::client_config(&servers, "group".into(), &config);
+        assert_eq!(config.get("auto.offset.reset"), Some("latest"));
+        assert_eq!(
+            OffsetReset::from_client_config(&config),
+            OffsetReset::Latest
+        );
+        assert_eq!(config.get("fetch.max.bytes"), Some("1048576"));
+        assert_eq!(config.get("group.id"), Some("group"));
+
+        let config = EventStream::client_config(&servers, "group".into(), &Default::default());
+        assert_eq!(config.get("auto.offset.reset"), Some("earliest"));
+        assert_eq!(config.get("fetch.max.bytes"), None);
+
+        let config = StreamConfig {
+            offset_reset: OffsetReset::Latest,
+            ..Default::default()
+        };
+        let config = EventStream::client_config(&servers, "group".into(), &config);
+        assert_eq!(config.get("auto.offset.reset"), Some("latest"));
+    }
+
+    struct TestProcessor {
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl EventProcessor for TestProcessor {
+        type Event = u32;
+
+        async fn process(&self, _event: u32) -> anyhow::Result<()> {
+            anyhow::ensure!(!self.fail, "database write failed");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn store_after_processing() {
+        let message = |payload: &[u8], offset| {
+            OwnedMessage::new(
+                Some(payload.to_vec()),
+                None,
+                "tags".into(),
+                Timestamp::NotAvailable,
+                0,
+                offset,
+                None,
+            )
+        };
+        let pending = Mutex::new(PendingOffsets::new(1));
+
+        // As if the consumer crashed right after a failed write, nothing is left to commit.
+        let failing = TestProcessor { fail: true };
+        process_message(&failing, Codec::Json, &pending, &message(b"1", 10))
             .await
+            .unwrap_err();
+        assert!(pending.lock().unwrap().take().is_empty());
+
+        let succeeding = TestProcessor { fail: false };
+        let offsets = process_message(&succeeding, Codec::Json, &pending, &message(b"1", 10))
+            .await
+            .unwrap();
+        assert_eq!(offsets, [(("tags".to_string(), 0), 10)]);
+
+        // An undecodable payload is not stored either.
+        process_message(&succeeding, Codec::Json, &pending, &message(b"{", 11))
+            .await
+            .unwrap_err();
+        assert!(pending.lock().unwrap().take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stop_consuming() {
+        let stop = tokio::time::sleep(Duration::from_millis(50));
+        let res =
+            tokio::time::timeout(Duration::from_secs(5), until(future::pending::<()>(), stop))
+                .await
+                .expect("should return soon after stop");
+        assert_eq!(res, None);
+
+        let res = until(future::ready(1), future::pending::<()>()).await;
+        assert_eq!(res, Some(1));
     }
 }
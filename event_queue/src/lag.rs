@@ -0,0 +1,142 @@
+use crate::consumer::OffsetReset;
+use anyhow::Context;
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    Offset,
+};
+use std::time::Duration;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub trait OffsetSource {
+    /// Committed offsets of the partitions assigned to this consumer, `None` if nothing was
+    /// committed yet.
+    fn committed(&self) -> anyhow::Result<Vec<(String, i32, Option<i64>)>>;
+
+    /// Low and high watermarks of the partition.
+    fn watermarks(&self, topic: &str, partition: i32) -> anyhow::Result<(i64, i64)>;
+}
+
+impl OffsetSource for StreamConsumer {
+    fn committed(&self) -> anyhow::Result<Vec<(String, i32, Option<i64>)>> {
+        let list = Consumer::committed(self, QUERY_TIMEOUT)
+            .context("failed to fetch committed offsets")?;
+
+        let committed = list
+            .elements()
+            .into_iter()
+            .map(|elem| {
+                let offset = match elem.offset() {
+                    Offset::Offset(offset) => Some(offset),
+                    _ => None,
+                };
+                (elem.topic().to_string(), elem.partition(), offset)
+            })
+            .collect();
+
+        Ok(committed)
+    }
+
+    fn watermarks(&self, topic: &str, partition: i32) -> anyhow::Result<(i64, i64)> {
+        self.fetch_watermarks(topic, partition, QUERY_TIMEOUT)
+            .with_context(|| format!("failed to fetch watermarks of {}/{}", topic, partition))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct PartitionLag {
+    pub topic: String,
+    pub partition: i32,
+    pub lag: i64,
+}
+
+pub fn compute_lag<S: OffsetSource>(
+    source: &S,
+    offset_reset: OffsetReset,
+) -> anyhow::Result<Vec<PartitionLag>> {
+    source
+        .committed()?
+        .into_iter()
+        .map(|(topic, partition, committed)| {
+            let (low, high) = source.watermarks(&topic, partition)?;
+            // Without a committed offset the consumer starts where its reset policy points.
+            let start = committed.unwrap_or(match offset_reset {
+                OffsetReset::Earliest => low,
+                OffsetReset::Latest => high,
+            });
+            let lag = (high - start).max(0);
+
+            Ok(PartitionLag {
+                topic,
+                partition,
+                lag,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct FakeOffsets {
+        committed: Vec<(String, i32, Option<i64>)>,
+        watermarks: HashMap<i32, (i64, i64)>,
+    }
+
+    impl OffsetSource for FakeOffsets {
+        fn committed(&self) -> anyhow::Result<Vec<(String, i32, Option<i64>)>> {
+            Ok(self.committed.clone())
+        }
+
+        fn watermarks(&self, _topic: &str, partition: i32) -> anyhow::Result<(i64, i64)> {
+            self.watermarks
+                .get(&partition)
+                .copied()
+                .context("unknown partition")
+        }
+    }
+
+    #[test]
+    fn lag() {
+        let source = FakeOffsets {
+            committed: vec![
+                ("tags".into(), 0, Some(90)),
+                ("tags".into(), 1, None),
+                ("tags".into(), 2, Some(50)),
+            ],
+            watermarks: HashMap::from([(0, (0, 100)), (1, (20, 30)), (2, (0, 50))]),
+        };
+
+        let expected = vec![
+            PartitionLag {
+                topic: "tags".into(),
+                partition: 0,
+                lag: 10,
+            },
+            PartitionLag {
+                topic: "tags".into(),
+                partition: 1,
+                lag: 10,
+            },
+            PartitionLag {
+                topic: "tags".into(),
+                partition: 2,
+                lag: 0,
+            },
+        ];
+        assert_eq!(
+            compute_lag(&source, OffsetReset::Earliest).unwrap(),
+            expected
+        );
+
+        // A group starting from the latest messages has no backlog before its first commit.
+        let lags = compute_lag(&source, OffsetReset::Latest)
+            .unwrap()
+            .into_iter()
+            .map(|lag| lag.lag)
+            .collect::<Vec<_>>();
+        assert_eq!(lags, [10, 0, 0]);
+    }
+}
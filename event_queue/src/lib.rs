@@ -1,2 +1,5 @@
+pub mod codec;
 pub mod consumer;
+pub mod lag;
 pub mod producer;
+pub mod retry;
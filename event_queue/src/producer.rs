@@ -1,20 +1,98 @@
+use crate::codec::Codec;
 use anyhow::{Context, Ok};
+use futures_util::{stream, Future, TryStreamExt};
 use rdkafka::{
-    producer::{FutureProducer, FutureRecord},
+    producer::{FutureProducer, FutureRecord, Producer},
     util::Timeout,
     ClientConfig,
 };
-use serde::Serialize;
-use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, time::Duration};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Acks {
+    #[serde(rename = "all")]
+    All,
+    #[serde(rename = "1")]
+    Leader,
+    #[serde(rename = "0")]
+    NoAcks,
+}
+
+impl Acks {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Leader => "1",
+            Self::NoAcks => "0",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProducerConfig {
+    pub acks: Acks,
+    /// Requires `acks` set to `All`.
+    pub enable_idempotence: bool,
+    pub codec: Codec,
+    /// Maximum number of undelivered records per [`EventProducer::produce_many`] call.
+    pub max_in_flight: usize,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> Self {
+        Self {
+            acks: Acks::All,
+            enable_idempotence: true,
+            codec: Default::default(),
+            max_in_flight: 128,
+        }
+    }
+}
 
 pub struct EventProducer {
     producer: FutureProducer,
     topic: String,
+    codec: Codec,
+    max_in_flight: usize,
 }
 
 impl EventProducer {
-    pub fn new(servers: &[SocketAddr], topic: String) -> anyhow::Result<Self> {
-        let producer: FutureProducer = ClientConfig::new()
+    pub fn new(
+        servers: &[SocketAddr],
+        topic: String,
+        config: &ProducerConfig,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !config.enable_idempotence || config.acks == Acks::All,
+            "idempotent producer requires acks=all"
+        );
+        anyhow::ensure!(config.max_in_flight > 0, "max_in_flight must be positive");
+
+        let producer: FutureProducer = Self::client_config(servers, config)
+            .create()
+            .context("failed to build the Kafka producer")?;
+
+        Ok(Self {
+            producer,
+            topic,
+            codec: config.codec,
+            max_in_flight: config.max_in_flight,
+        })
+    }
+
+    /// Fails if no broker answers within `timeout`. Blocks the calling thread.
+    pub fn check_connection(&self, timeout: Duration) -> anyhow::Result<()> {
+        self.producer
+            .client()
+            .fetch_metadata(None, timeout)
+            .context("failed to reach the Kafka brokers")?;
+        Ok(())
+    }
+
+    fn client_config(servers: &[SocketAddr], config: &ProducerConfig) -> ClientConfig {
+        let mut client_config = ClientConfig::new();
+        client_config
             .set(
                 "bootstrap.servers",
                 servers
@@ -23,22 +101,69 @@ impl EventProducer {
                     .collect::<Vec<_>>()
                     .join(","),
             )
-            .create()
-            .context("failed to build the Kafka producer")?;
+            .set("acks", config.acks.as_str())
+            .set("enable.idempotence", config.enable_idempotence.to_string());
 
-        Ok(Self { producer, topic })
+        client_config
     }
 
-    pub async fn produce<E: Serialize>(&self, event: &E) -> anyhow::Result<()> {
-        let serialized = serde_json::to_vec(event).expect("serialization to memory buffer failed");
-        let record: FutureRecord<[u8], _> = FutureRecord {
+    fn record<'a>(
+        &'a self,
+        payload: &'a [u8],
+        key: Option<&'a str>,
+    ) -> FutureRecord<'a, str, [u8]> {
+        FutureRecord {
             topic: &self.topic,
             partition: None,
-            payload: Some(&serialized),
-            key: None,
+            payload: Some(payload),
+            key,
             timestamp: None,
             headers: None,
-        };
+        }
+    }
+
+    pub async fn produce<E: Serialize>(&self, event: &E) -> anyhow::Result<()> {
+        self.send(event, None).await
+    }
+
+    /// Events with equal keys land in the same partition.
+    pub async fn produce_keyed<E, K>(&self, event: &E, key_fn: K) -> anyhow::Result<()>
+    where
+        E: Serialize,
+        K: Fn(&E) -> String,
+    {
+        let (payload, key) = self.keyed_payload(event, key_fn);
+        self.send_payload(&payload, Some(&key)).await
+    }
+
+    fn keyed_payload<E, K>(&self, event: &E, key_fn: K) -> (Vec<u8>, String)
+    where
+        E: Serialize,
+        K: Fn(&E) -> String,
+    {
+        (self.codec.encode(event), key_fn(event))
+    }
+
+    /// Sends all events concurrently, keeping at most `max_in_flight` of them undelivered.
+    /// Returns the first error, events that were not yet sent at that point are dropped.
+    pub async fn produce_many<E, K>(&self, events: &[(K, E)]) -> anyhow::Result<()>
+    where
+        E: Serialize,
+        K: AsRef<str>,
+    {
+        for_each_bounded(events, self.max_in_flight, |(key, event)| {
+            self.send(event, Some(key.as_ref()))
+        })
+        .await
+    }
+
+    async fn send<E: Serialize>(&self, event: &E, key: Option<&str>) -> anyhow::Result<()> {
+        let serialized = self.codec.encode(event);
+        self.send_payload(&serialized, key).await
+    }
+
+    async fn send_payload(&self, payload: &[u8], key: Option<&str>) -> anyhow::Result<()> {
+        let record = self.record(payload, key);
 
         self.producer
             .send(record, Timeout::Never)
@@ -49,3 +174,99 @@ impl EventProducer {
         Ok(())
     }
 }
+
+async fn for_each_bounded<'a, T, F, Fut>(items: &'a [T], limit: usize, f: F) -> anyhow::Result<()>
+where
+    F: Fn(&'a T) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    stream::iter(items.iter().map(anyhow::Ok))
+        .try_for_each_concurrent(limit, f)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    #[test]
+    fn record_key() {
+        let servers = ["127.0.0.1:9092".parse().unwrap()];
+        let producer = EventProducer::new(&servers, "events".into(), &Default::default()).unwrap();
+        let event = ("cookie".to_string(), 1);
+
+        // What `produce_keyed` sends.
+        let (payload, key) = producer.keyed_payload(&event, |event| event.0.clone());
+        let record = producer.record(&payload, Some(&key));
+        assert_eq!(record.key, Some("cookie"));
+        assert_eq!(record.topic, "events");
+        let decoded: (String, u32) = Codec::default().decode(record.payload.unwrap()).unwrap();
+        assert_eq!(decoded, event);
+
+        let record = producer.record(b"payload", None);
+        assert_eq!(record.key, None);
+    }
+
+    #[test]
+    fn durability_config() {
+        let servers = ["127.0.0.1:9092".parse().unwrap()];
+
+        let config = EventProducer::client_config(&servers, &Default::default());
+        assert_eq!(config.get("acks"), Some("all"));
+        assert_eq!(config.get("enable.idempotence"), Some("true"));
+
+        let config = ProducerConfig {
+            acks: Acks::Leader,
+            enable_idempotence: false,
+            ..Default::default()
+        };
+        let client_config = EventProducer::client_config(&servers, &config);
+        assert_eq!(client_config.get("acks"), Some("1"));
+        assert_eq!(client_config.get("enable.idempotence"), Some("false"));
+        EventProducer::new(&servers, "events".into(), &config).unwrap();
+
+        let config = ProducerConfig {
+            acks: Acks::NoAcks,
+            ..Default::default()
+        };
+        assert!(EventProducer::new(&servers, "events".into(), &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn bounded_batch() {
+        let in_flight = AtomicUsize::new(0);
+        let max_seen = AtomicUsize::new(0);
+        let delivered = Mutex::new(Vec::new());
+        let events = (0..20).map(|i| (i.to_string(), i)).collect::<Vec<_>>();
+
+        for_each_bounded(&events, 4, |(key, event)| {
+            let (in_flight, max_seen, delivered) = (&in_flight, &max_seen, &delivered);
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_seen.fetch_max(current, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                delivered.lock().unwrap().push((key.clone(), *event));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+
+        let mut delivered = delivered.into_inner().unwrap();
+        delivered.sort_by_key(|(_, event)| *event);
+        assert_eq!(delivered, events);
+        assert_eq!(max_seen.load(Ordering::SeqCst), 4);
+
+        let result = for_each_bounded(&events, 4, |(_, event)| async move {
+            anyhow::ensure!(*event != 7, "failed on {}", event);
+            Ok(())
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "failed on 7");
+    }
+}
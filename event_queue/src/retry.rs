@@ -0,0 +1,67 @@
+use std::{thread, time::Duration};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    /// Callers skip the connection check altogether when `0`.
+    pub attempts: u32,
+    pub interval: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Calls `f` until it succeeds, at most `attempts` times but at least once, sleeping `interval`
+/// in between. Meant for startup, when the brokers may not be up yet. Blocks the calling thread.
+pub fn retry<F: FnMut() -> anyhow::Result<()>>(
+    config: &RetryConfig,
+    mut f: F,
+) -> anyhow::Result<()> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match f() {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= config.attempts => {
+                return Err(e.context(format!("giving up after {} attempts", attempt)))
+            }
+            Err(_) => thread::sleep(config.interval),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_budget() {
+        let config = RetryConfig {
+            attempts: 3,
+            interval: Duration::from_millis(1),
+        };
+
+        let mut calls = 0;
+        retry(&config, || {
+            calls += 1;
+            anyhow::ensure!(calls == 3, "brokers not up yet");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let e = retry(&config, || {
+            calls += 1;
+            anyhow::bail!("brokers not up yet")
+        })
+        .unwrap_err();
+        assert_eq!(calls, 3);
+        assert_eq!(e.to_string(), "giving up after 3 attempts");
+    }
+}